@@ -1,10 +1,18 @@
 use std::path::PathBuf;
+use std::time::Duration;
+
+use clap::{Args, Parser, Subcommand, ValueEnum};
+use url::Url;
 
 #[cfg(feature = "server")]
-use clap::Args;
+use crate::favicon_image::fetch::HostRule;
 
-use clap::{Parser, Subcommand, ValueEnum};
-use url::Url;
+use crate::disk_cache::{DiskCache, DEFAULT_DISK_CACHE_MAX_BYTES, DEFAULT_DISK_CACHE_TTL};
+use crate::favicon_image::fetch::{
+    ColorScheme, FetchOptions, HostRules, IconRel, SelectionOptions, SizePolicy,
+    DEFAULT_CONNECT_TIMEOUT, DEFAULT_MAX_HTML_BYTES, DEFAULT_MAX_IMAGE_BYTES, DEFAULT_RETRIES,
+    DEFAULT_TIMEOUT, DEFAULT_TOTAL_TIMEOUT,
+};
 
 #[derive(Clone, ValueEnum, Debug)]
 pub enum ImageFormatOutput {
@@ -15,6 +23,8 @@ pub enum ImageFormatOutput {
     Ico,
     Gif,
     Tiff,
+    #[cfg(feature = "avif")]
+    Avif,
 }
 
 impl From<ImageFormatOutput> for image::ImageFormat {
@@ -27,10 +37,123 @@ impl From<ImageFormatOutput> for image::ImageFormat {
             ImageFormatOutput::Ico => image::ImageFormat::Ico,
             ImageFormatOutput::Gif => image::ImageFormat::Gif,
             ImageFormatOutput::Tiff => image::ImageFormat::Tiff,
+            #[cfg(feature = "avif")]
+            ImageFormatOutput::Avif => image::ImageFormat::Avif,
+        }
+    }
+}
+
+#[derive(Args, Debug)]
+pub struct SelectionArgs {
+    /// How to choose between icons of different sizes
+    #[arg(value_enum, long, default_value_t = SizePolicy::default())]
+    pub size_policy: SizePolicy,
+
+    /// Kinds of icon to prefer, in order (e.g. icon,apple-touch-icon)
+    #[arg(value_enum, long, value_delimiter = ',')]
+    pub rel_priority: Vec<IconRel>,
+
+    /// Colour scheme the favicon will be displayed in, used to pick between icons with media queries
+    #[arg(value_enum, long, default_value_t = ColorScheme::default())]
+    pub color_scheme: ColorScheme,
+}
+
+impl From<SelectionArgs> for SelectionOptions {
+    fn from(value: SelectionArgs) -> Self {
+        SelectionOptions {
+            size_policy: value.size_policy,
+            rel_priority: value.rel_priority,
+            color_scheme: value.color_scheme,
         }
     }
 }
 
+#[derive(Args, Debug)]
+pub struct FetchArgs {
+    /// Allow svg favicons to load images from the same origin over http
+    #[arg(long)]
+    pub svg_remote_images: bool,
+
+    /// Only use the first frame of animated favicons
+    #[arg(long = "static")]
+    pub static_only: bool,
+
+    /// Seconds allowed to connect to a server
+    #[arg(long, value_name = "SECONDS", default_value_t = DEFAULT_CONNECT_TIMEOUT.as_secs())]
+    pub connect_timeout: u64,
+
+    /// Seconds allowed for each request, including reading the response
+    #[arg(long, value_name = "SECONDS", default_value_t = DEFAULT_TIMEOUT.as_secs())]
+    pub timeout: u64,
+
+    /// Seconds allowed to fetch a favicon, including every request and retry
+    #[arg(long, value_name = "SECONDS", default_value_t = DEFAULT_TOTAL_TIMEOUT.as_secs())]
+    pub total_timeout: u64,
+
+    /// Largest html page or manifest to read, in bytes
+    #[arg(long, default_value_t = DEFAULT_MAX_HTML_BYTES)]
+    pub max_html_bytes: usize,
+
+    /// Largest favicon image to read, in bytes
+    #[arg(long, default_value_t = DEFAULT_MAX_IMAGE_BYTES)]
+    pub max_image_bytes: usize,
+
+    /// Times to retry requests that fail to connect or return a server error
+    #[arg(long, default_value_t = DEFAULT_RETRIES)]
+    pub retries: u32,
+
+    /// Always fetch favicons, without reading or writing the disk cache
+    #[arg(long)]
+    pub no_disk_cache: bool,
+
+    #[command(flatten)]
+    pub disk_cache: DiskCacheArgs,
+}
+
+impl From<FetchArgs> for FetchOptions {
+    fn from(value: FetchArgs) -> Self {
+        FetchOptions {
+            svg_remote_images: value.svg_remote_images,
+            static_only: value.static_only,
+            connect_timeout: Duration::from_secs(value.connect_timeout),
+            timeout: Duration::from_secs(value.timeout),
+            total_timeout: Duration::from_secs(value.total_timeout),
+            max_html_bytes: value.max_html_bytes,
+            max_image_bytes: value.max_image_bytes,
+            retries: value.retries,
+            // Only the server guards against fetching from private addresses
+            address_guard: None,
+            host_rules: HostRules::default(),
+            disk_cache: (!value.no_disk_cache).then(|| value.disk_cache.into()),
+        }
+    }
+}
+
+#[derive(Args, Debug)]
+pub struct DiskCacheArgs {
+    /// Directory to cache fetched favicons in [default: the user's cache directory]
+    #[arg(long, value_name = "PATH")]
+    pub disk_cache_dir: Option<PathBuf>,
+
+    /// Largest size of the disk cache, in bytes, before the oldest favicons are removed
+    #[arg(long, default_value_t = DEFAULT_DISK_CACHE_MAX_BYTES)]
+    pub disk_cache_max_bytes: u64,
+
+    /// Seconds to keep favicons in the disk cache for
+    #[arg(long, value_name = "SECONDS", default_value_t = DEFAULT_DISK_CACHE_TTL.as_secs())]
+    pub disk_cache_ttl: u64,
+}
+
+impl From<DiskCacheArgs> for DiskCache {
+    fn from(value: DiskCacheArgs) -> Self {
+        DiskCache::new(
+            value.disk_cache_dir.unwrap_or_else(DiskCache::default_dir),
+            value.disk_cache_max_bytes,
+            Duration::from_secs(value.disk_cache_ttl),
+        )
+    }
+}
+
 #[derive(Parser, Debug)]
 #[command(author, version, about)]
 pub struct Cli {
@@ -49,6 +172,12 @@ pub enum Command {
         #[arg(short, long)]
         size: Option<u32>,
 
+        #[command(flatten)]
+        selection: SelectionArgs,
+
+        #[command(flatten)]
+        fetch: FetchArgs,
+
         /// Path to save favicon to if not using stdout
         #[arg(short, long)]
         out: Option<PathBuf>,
@@ -56,6 +185,35 @@ pub enum Command {
         /// Image format to save favicon as (overrides file extension if provided)
         #[arg(value_enum, short, long)]
         format: Option<ImageFormatOutput>,
+
+        /// Square pixel sizes to include when saving as a multi-size ico (e.g. 16,32,48)
+        #[arg(long, value_delimiter = ',')]
+        ico_sizes: Vec<u32>,
+    },
+
+    /// List every favicon candidate for a specified url, from most to least suitable
+    List {
+        /// Host to list favicon candidates for
+        url: Url,
+
+        /// Square pixel size to rank candidates by
+        #[arg(short, long)]
+        size: Option<u32>,
+
+        #[command(flatten)]
+        selection: SelectionArgs,
+
+        #[command(flatten)]
+        fetch: FetchArgs,
+    },
+
+    /// Manage the favicons cached on disk
+    Cache {
+        #[command(flatten)]
+        disk_cache: DiskCacheArgs,
+
+        #[command(subcommand)]
+        command: CacheCommand,
     },
 
     /// Start a favicon rover web server
@@ -63,6 +221,25 @@ pub enum Command {
     Serve(ServerOptions),
 }
 
+#[derive(Subcommand, Debug)]
+pub enum CacheCommand {
+    /// List every cached favicon, from newest to oldest
+    List,
+
+    /// Show everything stored about a cached favicon
+    Inspect {
+        /// Id of the cached favicon, as shown by `cache list`
+        id: String,
+    },
+
+    /// Remove expired favicons that can't be revalidated, then the oldest favicons until the
+    /// cache fits its max size
+    Prune,
+
+    /// Remove every cached favicon
+    Clear,
+}
+
 #[cfg(feature = "server")]
 #[derive(Args, Debug)]
 pub struct ServerOptions {
@@ -77,4 +254,48 @@ pub struct ServerOptions {
     /// URL or regex allowed by CORS (multiple allowed)
     #[arg(short, long, default_values_t = [String::from("*")])]
     pub origin: Vec<String>,
+
+    /// Private or local address or CIDR range that favicons may be fetched from (multiple allowed)
+    #[arg(long, value_name = "ADDRESS", value_parser = parse_address_range)]
+    pub allow_address: Vec<ipnet::IpNet>,
+
+    /// Host, wildcard subdomain (*.example.com) or regex to only fetch favicons for (multiple allowed)
+    #[arg(long, value_name = "HOST")]
+    pub allow_host: Vec<HostRule>,
+
+    /// Host, wildcard subdomain (*.example.com) or regex to never fetch favicons for (multiple allowed)
+    #[arg(long, value_name = "HOST")]
+    pub deny_host: Vec<HostRule>,
+
+    /// Number of fetched favicons to keep in memory, or 0 to disable caching
+    #[arg(long, default_value_t = 256)]
+    pub cache_capacity: usize,
+
+    /// Approximate bytes of memory to use for fetched favicons
+    #[arg(long, value_name = "BYTES", default_value_t = 128 * 1024 * 1024)]
+    pub cache_max_bytes: usize,
+
+    /// Seconds to keep fetched favicons in memory for
+    #[arg(long, value_name = "SECONDS", default_value_t = 60 * 60 * 24)]
+    pub cache_ttl: u64,
+
+    /// Seconds after the cache ttl that a favicon is still served while it is refreshed in the background
+    #[arg(long, value_name = "SECONDS", default_value_t = 60 * 60 * 24 * 7)]
+    pub cache_stale_window: u64,
+
+    /// Favicon selection used unless set by the request
+    #[command(flatten)]
+    pub selection: SelectionArgs,
+
+    #[command(flatten)]
+    pub fetch: FetchArgs,
+}
+
+/// Parse a CIDR range, or a single ip address as a range containing only itself
+#[cfg(feature = "server")]
+fn parse_address_range(value: &str) -> Result<ipnet::IpNet, ipnet::AddrParseError> {
+    match value.parse::<std::net::IpAddr>() {
+        Ok(ip) => Ok(ip.into()),
+        Err(_) => value.parse(),
+    }
 }
@@ -0,0 +1,450 @@
+//! Persistent cache of fetched favicons on disk, shared by the cli and server
+
+use std::fmt;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use serde::{Deserialize, Serialize};
+use url::Url;
+
+use crate::favicon_image::fetch::{
+    FaviconCandidate, FaviconValidators, SelectionOptions, Validators,
+};
+
+pub const DEFAULT_DISK_CACHE_MAX_BYTES: u64 = 100 * 1024 * 1024;
+pub const DEFAULT_DISK_CACHE_TTL: Duration = Duration::from_secs(60 * 60 * 24 * 7);
+
+/// How long expired favicons with validators are kept, so they can still be revalidated
+const REVALIDATION_GRACE_PERIOD: Duration = Duration::from_secs(60 * 60 * 24 * 30);
+
+const METADATA_EXTENSION: &str = "json";
+const BODY_EXTENSION: &str = "bin";
+const TEMP_EXTENSION: &str = "tmp";
+
+/// Number of inserts between prunes, as pruning reads every entry in the cache
+const PRUNE_INTERVAL: usize = 64;
+
+/// Everything known about a cached favicon, stored next to its original bytes
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct CacheMetadata {
+    /// Url the favicon was fetched for
+    pub target_url: Url,
+    /// Options the favicon was chosen with
+    pub selection: SelectionOptions,
+    /// Square pixel size the favicon was chosen for
+    pub size: u32,
+    /// Candidate the favicon was fetched from, including its source url
+    pub candidate: FaviconCandidate,
+    pub content_type: Option<String>,
+    /// `ETag` and `Last-Modified` of the favicon
+    #[serde(flatten)]
+    pub validators: Validators,
+    /// `ETag` and `Last-Modified` of the page the favicon was found on
+    #[serde(default)]
+    pub page_validators: Validators,
+    /// Seconds since the unix epoch when the favicon was fetched or last revalidated
+    pub fetched_at: u64,
+}
+
+impl CacheMetadata {
+    /// Validators to check whether the cached favicon has changed
+    pub fn validators(&self) -> FaviconValidators {
+        FaviconValidators {
+            page: self.page_validators.clone(),
+            candidate: self.candidate.clone(),
+            icon: self.validators.clone(),
+        }
+    }
+}
+
+/// A favicon stored in the cache
+#[derive(Debug, Clone)]
+pub struct CacheEntry {
+    pub id: String,
+    pub metadata: CacheMetadata,
+    /// Size of the original favicon, in bytes
+    pub bytes: u64,
+    pub expired: bool,
+}
+
+impl fmt::Display for CacheEntry {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{}\t{}\tsource={:.64}\tbytes={}\tfetched={}\texpired={}",
+            self.id,
+            self.metadata.target_url,
+            self.metadata.candidate.url.as_str(),
+            self.bytes,
+            self.metadata.fetched_at,
+            self.expired,
+        )
+    }
+}
+
+/// Entries removed from the cache
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Removed {
+    pub entries: usize,
+    pub bytes: u64,
+}
+
+/// A directory of favicons, evicting expired entries and then the oldest entries
+/// once it grows larger than `max_bytes`
+#[derive(Debug, Clone)]
+pub struct DiskCache {
+    pub dir: PathBuf,
+    pub max_bytes: u64,
+    pub ttl: Duration,
+    /// Number of inserts made through this cache and its clones
+    inserts: Arc<AtomicUsize>,
+}
+
+impl DiskCache {
+    pub fn new(dir: PathBuf, max_bytes: u64, ttl: Duration) -> Self {
+        Self {
+            dir,
+            max_bytes,
+            ttl,
+            inserts: Arc::default(),
+        }
+    }
+
+    /// The user's cache directory, such as `~/.cache/favicon-rover`
+    pub fn default_dir() -> PathBuf {
+        std::env::var_os("XDG_CACHE_HOME")
+            .filter(|dir| !dir.is_empty())
+            .map(PathBuf::from)
+            .or_else(|| std::env::var_os("HOME").map(|home| PathBuf::from(home).join(".cache")))
+            .or_else(|| std::env::var_os("LOCALAPPDATA").map(PathBuf::from))
+            .unwrap_or_else(std::env::temp_dir)
+            .join("favicon-rover")
+    }
+
+    /// Get the metadata and original bytes of a favicon selected for `size`. Size policies can
+    /// choose different icons for different sizes, so each size is cached separately.
+    /// Expired favicons are kept until they are pruned, so they can be revalidated.
+    pub fn get(
+        &self,
+        target_url: &Url,
+        selection: &SelectionOptions,
+        size: u32,
+    ) -> Option<(CacheMetadata, Vec<u8>)> {
+        let id = entry_id(target_url, selection, size);
+        let metadata = self.read_metadata(&id).ok()?;
+
+        // Ids are hashes, so check the entry is really for this url
+        if metadata.target_url != *target_url
+            || metadata.selection != *selection
+            || metadata.size != size
+        {
+            return None;
+        }
+
+        let body = fs::read(self.path(&id, BODY_EXTENSION)).ok()?;
+        Some((metadata, body))
+    }
+
+    /// Store the original bytes of a fetched favicon. The cache is pruned on the first insert,
+    /// then every `PRUNE_INTERVAL` inserts, so it may briefly grow past `max_bytes`.
+    pub fn insert(&self, metadata: &CacheMetadata, body: &[u8]) -> io::Result<()> {
+        fs::create_dir_all(&self.dir)?;
+
+        // Write the body before the metadata, as entries without metadata are ignored
+        let id = entry_id(&metadata.target_url, &metadata.selection, metadata.size);
+        self.write_atomic(&self.path(&id, BODY_EXTENSION), body)?;
+        self.write_atomic(
+            &self.path(&id, METADATA_EXTENSION),
+            &serde_json::to_vec_pretty(metadata)?,
+        )?;
+
+        if self
+            .inserts
+            .fetch_add(1, Ordering::Relaxed)
+            .is_multiple_of(PRUNE_INTERVAL)
+        {
+            self.prune()?;
+        }
+        Ok(())
+    }
+
+    /// Every entry in the cache, from newest to oldest
+    pub fn entries(&self) -> io::Result<Vec<CacheEntry>> {
+        let dir = match fs::read_dir(&self.dir) {
+            Ok(dir) => dir,
+            Err(err) if err.kind() == io::ErrorKind::NotFound => return Ok(Vec::new()),
+            Err(err) => return Err(err),
+        };
+
+        let mut entries = Vec::new();
+        for file in dir {
+            let path = file?.path();
+            if path
+                .extension()
+                .is_some_and(|ext| ext == METADATA_EXTENSION)
+            {
+                // Skip entries that are unreadable, or were removed while listing
+                let id = path.file_stem().unwrap_or_default().to_string_lossy();
+                if let Ok(Some(entry)) = self.entry(&id) {
+                    entries.push(entry);
+                }
+            }
+        }
+
+        entries.sort_by_key(|entry| std::cmp::Reverse(entry.metadata.fetched_at));
+        Ok(entries)
+    }
+
+    /// Get a single entry by its id
+    pub fn entry(&self, id: &str) -> io::Result<Option<CacheEntry>> {
+        // Ids are hex hashes, so never read paths outside the cache directory
+        if id.is_empty() || !id.bytes().all(|byte| byte.is_ascii_hexdigit()) {
+            return Ok(None);
+        }
+        let metadata = match self.read_metadata(id) {
+            Ok(metadata) => metadata,
+            Err(err) if err.kind() == io::ErrorKind::NotFound => return Ok(None),
+            Err(err) => return Err(err),
+        };
+        let bytes = match fs::metadata(self.path(id, BODY_EXTENSION)) {
+            Ok(body) => body.len(),
+            Err(err) if err.kind() == io::ErrorKind::NotFound => return Ok(None),
+            Err(err) => return Err(err),
+        };
+
+        Ok(Some(CacheEntry {
+            id: id.to_owned(),
+            expired: self.is_expired(&metadata),
+            metadata,
+            bytes,
+        }))
+    }
+
+    /// Remove expired entries that can't be revalidated, then the oldest entries until the
+    /// cache fits in `max_bytes`
+    pub fn prune(&self) -> io::Result<Removed> {
+        let mut removed = Removed::default();
+        let mut total_bytes = 0;
+        for entry in self.entries()? {
+            if !self.can_revalidate(&entry) || total_bytes + entry.bytes > self.max_bytes {
+                self.remove(&entry.id)?;
+                removed.entries += 1;
+                removed.bytes += entry.bytes;
+            } else {
+                total_bytes += entry.bytes;
+            }
+        }
+
+        Ok(removed)
+    }
+
+    /// Remove every entry
+    pub fn clear(&self) -> io::Result<Removed> {
+        let mut removed = Removed::default();
+        for entry in self.entries()? {
+            self.remove(&entry.id)?;
+            removed.entries += 1;
+            removed.bytes += entry.bytes;
+        }
+
+        Ok(removed)
+    }
+
+    fn remove(&self, id: &str) -> io::Result<()> {
+        for extension in [METADATA_EXTENSION, BODY_EXTENSION] {
+            match fs::remove_file(self.path(id, extension)) {
+                Err(err) if err.kind() != io::ErrorKind::NotFound => return Err(err),
+                _ => {}
+            }
+        }
+        Ok(())
+    }
+
+    pub fn is_expired(&self, metadata: &CacheMetadata) -> bool {
+        unix_now().saturating_sub(metadata.fetched_at) >= self.ttl.as_secs()
+    }
+
+    /// Whether an entry is fresh, or expired but has validators and is within the grace period
+    fn can_revalidate(&self, entry: &CacheEntry) -> bool {
+        let metadata = &entry.metadata;
+        let has_validators =
+            !metadata.validators.is_empty() || !metadata.page_validators.is_empty();
+        let age = unix_now().saturating_sub(metadata.fetched_at);
+        !entry.expired || (has_validators && age < (self.ttl + REVALIDATION_GRACE_PERIOD).as_secs())
+    }
+
+    fn read_metadata(&self, id: &str) -> io::Result<CacheMetadata> {
+        let metadata = fs::read(self.path(id, METADATA_EXTENSION))?;
+        Ok(serde_json::from_slice(&metadata)?)
+    }
+
+    fn path(&self, id: &str, extension: &str) -> PathBuf {
+        self.dir.join(id).with_extension(extension)
+    }
+
+    /// Write a file by renaming a temporary file over it, so it is never read half written
+    fn write_atomic(&self, path: &Path, data: &[u8]) -> io::Result<()> {
+        let nanos = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .subsec_nanos();
+        let temp_path =
+            path.with_extension(format!("{}.{nanos}.{TEMP_EXTENSION}", std::process::id()));
+        fs::write(&temp_path, data)?;
+        if let Err(err) = fs::rename(&temp_path, path) {
+            let _ = fs::remove_file(&temp_path);
+            return Err(err);
+        }
+        Ok(())
+    }
+}
+
+/// Seconds since the unix epoch
+pub fn unix_now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs()
+}
+
+/// Id of the cache entry for a url, selection options and size, as a 64 bit FNV-1a hash
+fn entry_id(target_url: &Url, selection: &SelectionOptions, size: u32) -> String {
+    let key = serde_json::to_string(&(target_url, selection, size)).expect("Key should serialise");
+    let hash = key.bytes().fold(0xcbf29ce484222325_u64, |hash, byte| {
+        (hash ^ byte as u64).wrapping_mul(0x100000001b3)
+    });
+    format!("{hash:016x}")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn cache(name: &str, max_bytes: u64, ttl: Duration) -> DiskCache {
+        let dir = std::env::temp_dir().join(format!(
+            "favicon-rover-disk-cache-{name}-{}",
+            std::process::id()
+        ));
+        let _ = fs::remove_dir_all(&dir);
+        DiskCache::new(dir, max_bytes, ttl)
+    }
+
+    fn metadata(url: &str, fetched_at: u64) -> CacheMetadata {
+        let target_url = Url::parse(url).unwrap();
+        CacheMetadata {
+            candidate: FaviconCandidate::default_for(&target_url),
+            target_url,
+            selection: SelectionOptions::default(),
+            size: 256,
+            content_type: Some("image/png".to_owned()),
+            validators: Validators {
+                etag: Some("\"abc\"".to_owned()),
+                last_modified: None,
+            },
+            page_validators: Validators::default(),
+            fetched_at,
+        }
+    }
+
+    #[test]
+    fn stores_and_reads_entries() {
+        let cache = cache("roundtrip", 1024, Duration::from_secs(60));
+        let metadata = metadata("https://example.com/", unix_now());
+        cache.insert(&metadata, b"icon").unwrap();
+
+        let selection = SelectionOptions::default();
+        let (read, body) = cache.get(&metadata.target_url, &selection, 256).unwrap();
+        assert_eq!(body, b"icon");
+        assert_eq!(read.validators(), metadata.validators());
+        assert!(cache.get(&metadata.target_url, &selection, 16).is_none());
+        assert!(cache.get(&metadata.target_url, &selection, 512).is_none());
+
+        // Each size is a separate entry
+        let small = CacheMetadata {
+            size: 16,
+            ..metadata.clone()
+        };
+        cache.insert(&small, b"small").unwrap();
+        let (_, body) = cache.get(&metadata.target_url, &selection, 16).unwrap();
+        assert_eq!(body, b"small");
+        let (_, body) = cache.get(&metadata.target_url, &selection, 256).unwrap();
+        assert_eq!(body, b"icon");
+
+        assert_eq!(cache.entries().unwrap().len(), 2);
+        assert_eq!(cache.clear().unwrap().entries, 2);
+        assert!(cache.entries().unwrap().is_empty());
+        fs::remove_dir_all(&cache.dir).unwrap();
+    }
+
+    #[test]
+    fn prunes_expired_and_oldest_entries() {
+        let cache = cache("prune", 8, Duration::from_secs(60));
+        let now = unix_now();
+        cache
+            .insert(&metadata("https://old.com/", now - 10), b"1234")
+            .unwrap();
+        cache
+            .insert(&metadata("https://new.com/", now), b"1234")
+            .unwrap();
+        cache
+            .insert(&metadata("https://newest.com/", now + 1), b"1234")
+            .unwrap();
+
+        // Only the first insert prunes
+        let expired = CacheMetadata {
+            validators: Validators::default(),
+            ..metadata("https://expired.com/", now - 120)
+        };
+        cache.insert(&expired, b"1").unwrap();
+        assert_eq!(cache.entries().unwrap().len(), 4);
+        cache.prune().unwrap();
+
+        let hosts: Vec<String> = cache
+            .entries()
+            .unwrap()
+            .into_iter()
+            .map(|entry| entry.metadata.target_url.host_str().unwrap().to_owned())
+            .collect();
+        assert_eq!(hosts, ["newest.com", "new.com"]);
+        fs::remove_dir_all(&cache.dir).unwrap();
+    }
+
+    #[test]
+    fn keeps_expired_entries_with_validators() {
+        let cache = cache("revalidate", 1024, Duration::from_secs(60));
+        let now = unix_now();
+        let selection = SelectionOptions::default();
+        let expired = [
+            metadata("https://a.com/", now - 120),
+            metadata("https://b.com/", now - 120),
+        ];
+        for metadata in &expired {
+            cache.insert(metadata, b"icon").unwrap();
+        }
+        cache
+            .insert(&metadata("https://c.com/", now), b"icon")
+            .unwrap();
+        cache.prune().unwrap();
+
+        for metadata in &expired {
+            let (read, _) = cache.get(&metadata.target_url, &selection, 256).unwrap();
+            assert!(cache.is_expired(&read));
+            assert_eq!(read.validators(), metadata.validators());
+        }
+
+        // Until the grace period has passed
+        let forgotten = metadata(
+            "https://d.com/",
+            now - 120 - REVALIDATION_GRACE_PERIOD.as_secs(),
+        );
+        cache.insert(&forgotten, b"icon").unwrap();
+        cache.prune().unwrap();
+        assert!(cache.get(&forgotten.target_url, &selection, 256).is_none());
+        assert_eq!(cache.entries().unwrap().len(), 3);
+        fs::remove_dir_all(&cache.dir).unwrap();
+    }
+}
@@ -0,0 +1,366 @@
+//! Animation operations for favicon images
+
+use std::borrow::Cow;
+use std::io;
+
+use image::{
+    codecs::{
+        gif::{GifDecoder, GifEncoder, Repeat},
+        png::PngDecoder,
+    },
+    imageops::FilterType,
+    AnimationDecoder, Delay, DynamicImage, Frame, Frames, ImageFormat, RgbaImage,
+};
+
+use super::{WriteImageError, WEBP_QUALITY};
+
+/// Most frames kept from an animation
+const MAX_FRAMES: usize = 256;
+/// Most bytes of decoded frames kept from an animation
+const MAX_DECODED_BYTES: usize = 64 * 1024 * 1024;
+
+const VP8X_ANIMATION_FLAG: u8 = 0x02;
+
+/// A single frame of an animated favicon
+#[derive(Debug, Clone)]
+pub struct AnimationFrame {
+    pub data: RgbaImage,
+    /// How long the frame is shown before the next one
+    pub delay: Delay,
+}
+
+impl From<Frame> for AnimationFrame {
+    fn from(frame: Frame) -> Self {
+        Self {
+            delay: frame.delay(),
+            data: frame.into_buffer(),
+        }
+    }
+}
+
+impl AnimationFrame {
+    fn delay_ms(&self) -> u32 {
+        let (numer, denom) = self.delay.numer_denom_ms();
+        numer / denom.max(1)
+    }
+
+    pub(super) fn resize(self, size: u32) -> Self {
+        let data = DynamicImage::ImageRgba8(self.data)
+            .resize_to_fill(size, size, FilterType::Lanczos3)
+            .into_rgba8();
+        Self { data, ..self }
+    }
+
+    pub(super) fn tint(mut self, color: image::Rgb<u8>) -> Self {
+        for pixel in self.data.pixels_mut() {
+            pixel.0[..3].copy_from_slice(&color.0);
+        }
+        self
+    }
+}
+
+/// Decode every frame of a gif, animated png or animated webp. Returns `None` for
+/// other formats, and for still pngs and webps, which should be decoded normally.
+/// Still gifs, and animations with too many frames or decoded bytes, have only
+/// their first frame.
+pub fn decode_frames(data: &[u8], format: ImageFormat) -> Option<Vec<AnimationFrame>> {
+    let frames = match format {
+        ImageFormat::Gif => {
+            limit_frames(GifDecoder::new(io::Cursor::new(data)).ok()?.into_frames())?
+        }
+        ImageFormat::Png => {
+            let decoder = PngDecoder::new(io::Cursor::new(data)).ok()?;
+            if !decoder.is_apng() {
+                return None;
+            }
+            limit_frames(decoder.apng().into_frames())?
+        }
+        ImageFormat::WebP => decode_webp_frames(data)?,
+        _ => return None,
+    };
+
+    (!frames.is_empty()).then_some(frames)
+}
+
+/// Decode frames until the limits are reached, keeping only the first frame if they are
+fn limit_frames(frames: Frames) -> Option<Vec<AnimationFrame>> {
+    let mut kept = Vec::new();
+    let mut decoded_bytes = 0;
+    for frame in frames {
+        let frame = AnimationFrame::from(frame.ok()?);
+        decoded_bytes += frame.data.as_raw().len();
+        kept.push(frame);
+        if kept.len() > MAX_FRAMES || decoded_bytes > MAX_DECODED_BYTES {
+            kept.truncate(1);
+            break;
+        }
+    }
+    Some(kept)
+}
+
+/// The chunks of a webp file as their fourcc and bytes, including the chunk header
+fn webp_chunks(data: &[u8]) -> Option<Vec<([u8; 4], &[u8])>> {
+    if data.get(..4)? != b"RIFF" || data.get(8..12)? != b"WEBP" {
+        return None;
+    }
+
+    let mut chunks = Vec::new();
+    let mut rest = &data[12..];
+    while rest.len() >= 8 {
+        let fourcc = rest[..4].try_into().unwrap();
+        let size = u32::from_le_bytes(rest[4..8].try_into().unwrap()) as usize;
+        // Chunks are padded to an even size
+        let len = (8 + size + size % 2).min(rest.len());
+        chunks.push((fourcc, &rest[..len]));
+        rest = &rest[len..];
+    }
+    Some(chunks)
+}
+
+/// Decode every frame of an animated webp with the `webp` crate
+fn decode_webp_frames(data: &[u8]) -> Option<Vec<AnimationFrame>> {
+    // Check the webp is animated before decoding it
+    let chunks = webp_chunks(data)?;
+    let (_, vp8x) = chunks.iter().find(|(fourcc, _)| fourcc == b"VP8X")?;
+    if vp8x.get(8)? & VP8X_ANIMATION_FLAG == 0 {
+        return None;
+    }
+    let canvas_dimension = |i: usize| {
+        let bytes = vp8x.get(i..i + 3)?;
+        Some(u32::from_le_bytes([bytes[0], bytes[1], bytes[2], 0]) as usize + 1)
+    };
+    let frame_bytes = canvas_dimension(12)? * canvas_dimension(15)? * 4;
+    let frame_count = chunks
+        .iter()
+        .filter(|(fourcc, _)| fourcc == b"ANMF")
+        .count();
+
+    // Remove every frame but the first from webps over the limits
+    let data = if frame_count > MAX_FRAMES || frame_count * frame_bytes > MAX_DECODED_BYTES {
+        let mut body = b"WEBP".to_vec();
+        let mut frames = 0;
+        for (fourcc, chunk) in &chunks {
+            if fourcc == b"ANMF" {
+                frames += 1;
+                if frames > 1 {
+                    continue;
+                }
+            }
+            body.extend_from_slice(chunk);
+        }
+        let mut webp = b"RIFF".to_vec();
+        webp.extend_from_slice(&(body.len() as u32).to_le_bytes());
+        webp.extend_from_slice(&body);
+        Cow::Owned(webp)
+    } else {
+        Cow::Borrowed(data)
+    };
+
+    let animation = webp::AnimDecoder::new(&data).decode().ok()?;
+
+    // Frame timestamps mark when each frame ends
+    let mut start = 0;
+    let frames = animation
+        .into_iter()
+        .map(|frame| {
+            let image: DynamicImage = (&frame).into();
+            let end = frame.get_time_ms().max(start);
+            let delay = Delay::from_numer_denom_ms((end - start) as u32, 1);
+            start = end;
+            AnimationFrame {
+                data: image.into_rgba8(),
+                delay,
+            }
+        })
+        .collect();
+    Some(frames)
+}
+
+impl super::FaviconImage {
+    /// Whether the image has more than one frame
+    pub fn is_animated(&self) -> bool {
+        self.frames.len() > 1
+    }
+
+    pub(super) fn write_to_animated_gif(
+        &self,
+        writer: &mut impl io::Write,
+    ) -> Result<(), WriteImageError> {
+        let mut encoder = GifEncoder::new(writer);
+        encoder.set_repeat(Repeat::Infinite)?;
+        encoder.encode_frames(
+            self.frames
+                .iter()
+                .map(|frame| Frame::from_parts(frame.data.clone(), 0, 0, frame.delay)),
+        )?;
+
+        Ok(())
+    }
+
+    pub(super) fn write_to_animated_webp(
+        &self,
+        writer: &mut impl io::Write,
+    ) -> Result<(), WriteImageError> {
+        let mut config =
+            webp::WebPConfig::new().map_err(|_| WriteImageError::UnsupportedImageFormat)?;
+        config.quality = WEBP_QUALITY;
+
+        // Frames are placed at the time they start, and must all be the same size
+        let (width, height) = (self.data.width(), self.data.height());
+        let mut encoder = webp::AnimEncoder::new(width, height, &config);
+        let mut timestamp = 0;
+        for frame in &self.frames {
+            if frame.data.dimensions() != (width, height) {
+                return Err(WriteImageError::UnsupportedImageFormat);
+            }
+            encoder.add_frame(webp::AnimFrame::from_rgba(
+                frame.data.as_raw(),
+                width,
+                height,
+                timestamp as i32,
+            ));
+            timestamp += frame.delay_ms();
+        }
+        let webp = encoder
+            .try_encode()
+            .map_err(|_| WriteImageError::UnsupportedImageFormat)?;
+        writer.write_all(webp.as_ref())?;
+
+        Ok(())
+    }
+}
+
+/// Build the still image shown for an animation, which is its first frame
+pub fn first_frame(frames: &[AnimationFrame]) -> DynamicImage {
+    DynamicImage::ImageRgba8(frames[0].data.clone())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::favicon_image::FaviconImage;
+    use image::Rgba;
+
+    fn gif_with_frames(count: usize) -> Vec<u8> {
+        let mut gif = Vec::new();
+        let mut encoder = GifEncoder::new(&mut gif);
+        for i in 0..count {
+            let color = match i % 2 {
+                0 => [255, 0, 0, 255],
+                _ => [0, 0, 255, 255],
+            };
+            let buffer = RgbaImage::from_pixel(8, 8, Rgba(color));
+            let delay = Delay::from_numer_denom_ms(100, 1);
+            encoder
+                .encode_frame(Frame::from_parts(buffer, 0, 0, delay))
+                .unwrap();
+        }
+        drop(encoder);
+        gif
+    }
+
+    fn two_frame_gif() -> Vec<u8> {
+        gif_with_frames(2)
+    }
+
+    fn animated_image() -> FaviconImage {
+        let frames = decode_frames(&two_frame_gif(), ImageFormat::Gif).unwrap();
+        let data = first_frame(&frames);
+        FaviconImage {
+            format: Some(ImageFormat::Gif),
+            frames,
+            ..FaviconImage::from_data(data)
+        }
+    }
+
+    #[test]
+    fn decodes_gif_frames_with_delays() {
+        let frames = decode_frames(&two_frame_gif(), ImageFormat::Gif).unwrap();
+        assert_eq!(frames.len(), 2);
+        assert_eq!(frames[1].data.get_pixel(4, 4).0, [0, 0, 255, 255]);
+        assert_eq!(frames[0].delay_ms(), 100);
+    }
+
+    #[test]
+    fn still_images_have_no_frames() {
+        let mut png = io::Cursor::new(Vec::new());
+        DynamicImage::new_rgba8(8, 8)
+            .write_to(&mut png, image::ImageOutputFormat::Png)
+            .unwrap();
+        assert!(decode_frames(png.get_ref(), ImageFormat::Png).is_none());
+
+        let mut webp = io::Cursor::new(Vec::new());
+        FaviconImage::from_data(DynamicImage::new_rgba8(8, 8))
+            .write_to(&mut webp, ImageFormat::WebP)
+            .unwrap();
+        assert!(decode_frames(webp.get_ref(), ImageFormat::WebP).is_none());
+    }
+
+    #[test]
+    fn still_gifs_have_one_frame() {
+        let frames = decode_frames(&gif_with_frames(1), ImageFormat::Gif).unwrap();
+        assert_eq!(frames.len(), 1);
+    }
+
+    #[test]
+    fn long_animations_keep_their_first_frame() {
+        let frames = decode_frames(&gif_with_frames(MAX_FRAMES + 1), ImageFormat::Gif).unwrap();
+        assert_eq!(frames.len(), 1);
+        assert_eq!(frames[0].data.get_pixel(4, 4).0, [255, 0, 0, 255]);
+
+        let mut image = animated_image();
+        image.frames = image
+            .frames
+            .iter()
+            .cycle()
+            .take(MAX_FRAMES + 1)
+            .cloned()
+            .collect();
+        let mut webp = io::Cursor::new(Vec::new());
+        image.write_to(&mut webp, ImageFormat::WebP).unwrap();
+        assert_eq!(
+            webp_chunks(webp.get_ref())
+                .unwrap()
+                .iter()
+                .filter(|(fourcc, _)| fourcc == b"ANMF")
+                .count(),
+            MAX_FRAMES + 1
+        );
+        let frames = decode_frames(webp.get_ref(), ImageFormat::WebP).unwrap();
+        assert_eq!(frames.len(), 1);
+        assert!(frames[0].data.get_pixel(4, 4).0[0] > 200);
+    }
+
+    #[test]
+    fn resizes_every_frame() {
+        let image = animated_image().resize(4);
+        assert!(image.is_animated());
+        assert!(image
+            .frames
+            .iter()
+            .all(|frame| frame.data.dimensions() == (4, 4)));
+    }
+
+    #[test]
+    fn writes_animated_gif() {
+        let mut gif = io::Cursor::new(Vec::new());
+        animated_image()
+            .write_to(&mut gif, ImageFormat::Gif)
+            .unwrap();
+        let frames = decode_frames(gif.get_ref(), ImageFormat::Gif).unwrap();
+        assert_eq!(frames.len(), 2);
+    }
+
+    #[test]
+    fn writes_animated_webp() {
+        let mut webp = io::Cursor::new(Vec::new());
+        animated_image()
+            .write_to(&mut webp, ImageFormat::WebP)
+            .unwrap();
+        let frames = decode_frames(webp.get_ref(), ImageFormat::WebP).unwrap();
+        assert_eq!(frames.len(), 2);
+        assert_eq!(frames[0].delay_ms(), 100);
+        // Webp output is lossy
+        assert!(frames[1].data.get_pixel(4, 4).0[2] > 200);
+    }
+}
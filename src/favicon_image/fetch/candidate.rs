@@ -0,0 +1,549 @@
+//! Describes a possible favicon url found for a website
+
+use std::fmt;
+
+use clap::ValueEnum;
+use serde::{Deserialize, Serialize};
+use url::Url;
+
+/// How to choose between icons of different sizes
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Hash, ValueEnum, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum SizePolicy {
+    /// The smallest icon at or above the preferred size, otherwise the largest available
+    #[default]
+    SmallestAtOrAbove,
+    /// The icon closest to the preferred size, preferring larger icons when tied
+    Closest,
+    /// The largest icon available
+    Largest,
+}
+
+impl SizePolicy {
+    /// Ordering key for an icon width, where lower keys are more preferred
+    fn rank_key(self, width: u32, preferred_size: u32) -> (u32, u32) {
+        let below = (width < preferred_size) as u32;
+        match self {
+            SizePolicy::SmallestAtOrAbove => (below, width.abs_diff(preferred_size)),
+            SizePolicy::Closest => (width.abs_diff(preferred_size), below),
+            SizePolicy::Largest => (u32::MAX - width, 0),
+        }
+    }
+}
+
+/// The kind of icon a link tag declares with its `rel` attribute
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, ValueEnum, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum IconRel {
+    /// `icon` or `shortcut icon`, also used for manifest and default icons
+    Icon,
+    /// `apple-touch-icon` or `apple-touch-icon-precomposed`
+    AppleTouchIcon,
+    /// `mask-icon`, a monochrome svg tinted with its `color` attribute
+    MaskIcon,
+}
+
+impl IconRel {
+    /// Determine the kind of icon from a `rel` attribute
+    pub fn from_rel(rel: &str) -> Self {
+        let rel = rel.to_ascii_lowercase();
+        let mut tokens = rel.split_ascii_whitespace();
+        if tokens.clone().any(|token| token == "mask-icon") {
+            IconRel::MaskIcon
+        } else if tokens.any(|token| token.starts_with("apple-touch-icon")) {
+            IconRel::AppleTouchIcon
+        } else {
+            IconRel::Icon
+        }
+    }
+}
+
+/// The colour scheme the favicon will be displayed in
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Hash, ValueEnum, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum ColorScheme {
+    /// Prefer icons for a light colour scheme
+    Light,
+    /// Prefer icons for a dark colour scheme
+    Dark,
+    /// Prefer icons without a colour scheme, excluding icons for a dark colour scheme
+    #[default]
+    NoPreference,
+}
+
+impl ColorScheme {
+    /// Ordering key for an icon targeting a colour scheme, where lower keys are more
+    /// preferred, or `None` if the icon is for the opposite colour scheme
+    fn rank_key(self, target: Option<ColorScheme>) -> Option<u32> {
+        match target {
+            None if self == ColorScheme::NoPreference => Some(0),
+            None => Some(1),
+            Some(target) if target == self => Some(0),
+            // Icons for a light colour scheme are also fine when there is no preference
+            Some(ColorScheme::Light) if self == ColorScheme::NoPreference => Some(1),
+            Some(_) => None,
+        }
+    }
+}
+
+/// Preferences used to choose between the favicon candidates of a site
+#[derive(Debug, Clone, Default, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub struct SelectionOptions {
+    pub size_policy: SizePolicy,
+    /// Kinds of icon to prefer, in order. Unlisted kinds are ranked after
+    /// listed ones, with mask icons ranked last.
+    pub rel_priority: Vec<IconRel>,
+    pub color_scheme: ColorScheme,
+}
+
+/// Where a favicon candidate was discovered
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum CandidateSource {
+    /// A `<link rel="icon">` tag in the page
+    Link,
+    /// The `icons` array of a web app manifest
+    Manifest,
+    /// The conventional `/favicon.ico` location
+    Default,
+}
+
+/// A size declared by a `sizes` attribute
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum IconSize {
+    /// The icon is scalable and can be rendered at any size
+    Any,
+    Fixed {
+        width: u32,
+        height: u32,
+    },
+}
+
+/// A possible favicon for a website
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct FaviconCandidate {
+    pub url: Url,
+    /// The declared `sizes`, empty if unknown
+    pub sizes: Vec<IconSize>,
+    /// The declared mime type, if any
+    pub mime_type: Option<String>,
+    pub source: CandidateSource,
+    /// The `rel` attribute of the link tag, if any
+    pub rel: Option<String>,
+    /// The `color` attribute of a mask icon, if any
+    pub color: Option<String>,
+    /// The `media` query of the link tag, if any
+    pub media: Option<String>,
+}
+
+impl FaviconCandidate {
+    /// The conventional `/favicon.ico` candidate for a url
+    pub fn default_for(url: &Url) -> Self {
+        Self {
+            url: url.join("/favicon.ico").unwrap(),
+            sizes: Vec::new(),
+            mime_type: None,
+            source: CandidateSource::Default,
+            rel: None,
+            color: None,
+            media: None,
+        }
+    }
+
+    /// The colour scheme targeted by this candidate's media query, if any
+    pub fn color_scheme(&self) -> Option<ColorScheme> {
+        let media = self.media.as_deref()?.replace(' ', "").to_ascii_lowercase();
+        if media.contains("prefers-color-scheme:dark") {
+            Some(ColorScheme::Dark)
+        } else if media.contains("prefers-color-scheme:light") {
+            Some(ColorScheme::Light)
+        } else {
+            None
+        }
+    }
+
+    /// The kind of icon this candidate is
+    pub fn icon_rel(&self) -> IconRel {
+        self.rel
+            .as_deref()
+            .map(IconRel::from_rel)
+            .unwrap_or(IconRel::Icon)
+    }
+
+    /// The colour to tint a mask icon with, defaulting to black
+    pub fn mask_color(&self) -> image::Rgb<u8> {
+        self.color
+            .as_deref()
+            .and_then(parse_hex_color)
+            .unwrap_or(image::Rgb([0, 0, 0]))
+    }
+
+    /// Whether this candidate can be rendered at any size without losing quality
+    pub fn is_scalable(&self) -> bool {
+        self.icon_rel() == IconRel::MaskIcon
+            || self.sizes.contains(&IconSize::Any)
+            || self.mime_type.as_deref().is_some_and(|mime_type| {
+                mime_type
+                    .trim()
+                    .to_ascii_lowercase()
+                    .starts_with("image/svg+xml")
+            })
+    }
+
+    /// Ordering key of the declared width that best matches the preferred size
+    fn rank_key(&self, policy: SizePolicy, preferred_size: u32) -> (u32, u32) {
+        self.sizes
+            .iter()
+            .filter_map(|size| match size {
+                IconSize::Fixed { width, .. } => Some(policy.rank_key(*width, preferred_size)),
+                IconSize::Any => None,
+            })
+            .min()
+            // Unknown sizes are ranked as 0
+            .unwrap_or_else(|| policy.rank_key(0, preferred_size))
+    }
+
+    /// Position of this candidate's kind of icon in a rel priority order
+    fn rel_rank(&self, rel_priority: &[IconRel]) -> usize {
+        let rel = self.icon_rel();
+        rel_priority
+            .iter()
+            .position(|priority| *priority == rel)
+            .unwrap_or(rel_priority.len() + (rel == IconRel::MaskIcon) as usize)
+    }
+}
+
+/// Parse a `#rgb` or `#rrggbb` colour
+fn parse_hex_color(color: &str) -> Option<image::Rgb<u8>> {
+    let hex = color.trim().strip_prefix('#')?;
+    let len = match hex.len() {
+        3 => 1,
+        6 => 2,
+        _ => return None,
+    };
+    let channel = |i: usize| {
+        let value = u8::from_str_radix(hex.get(i * len..(i + 1) * len)?, 16).ok()?;
+        Some(if len == 1 { value * 0x11 } else { value })
+    };
+    Some(image::Rgb([channel(0)?, channel(1)?, channel(2)?]))
+}
+
+/// Parse a `sizes` attribute, a space separated list of `any` or `WxH` sizes
+pub fn parse_sizes(sizes: &str) -> Vec<IconSize> {
+    sizes
+        .split_ascii_whitespace()
+        .filter_map(|size| {
+            if size.eq_ignore_ascii_case("any") {
+                return Some(IconSize::Any);
+            }
+            let (width, height) = size.split_once(['x', 'X'])?;
+            Some(IconSize::Fixed {
+                width: width.parse().ok()?,
+                height: height.parse().ok()?,
+            })
+        })
+        .collect()
+}
+
+impl fmt::Display for IconSize {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            IconSize::Any => write!(f, "any"),
+            IconSize::Fixed { width, height } => write!(f, "{}x{}", width, height),
+        }
+    }
+}
+
+impl fmt::Display for CandidateSource {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            CandidateSource::Link => write!(f, "link"),
+            CandidateSource::Manifest => write!(f, "manifest"),
+            CandidateSource::Default => write!(f, "default"),
+        }
+    }
+}
+
+impl fmt::Display for FaviconCandidate {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let sizes = self
+            .sizes
+            .iter()
+            .map(|size| size.to_string())
+            .collect::<Vec<_>>()
+            .join(" ");
+        write!(
+            f,
+            "{}\tsizes={}\ttype={}\tsource={}\trel={}\tcolor={}\tmedia={}",
+            self.url,
+            if sizes.is_empty() { "-" } else { &sizes },
+            self.mime_type.as_deref().unwrap_or("-"),
+            self.source,
+            self.rel.as_deref().unwrap_or("-"),
+            self.color.as_deref().unwrap_or("-"),
+            self.media.as_deref().unwrap_or("-"),
+        )
+    }
+}
+
+/// Sort candidates so that the most suitable for the preferred size comes first
+pub fn rank_candidates(
+    mut candidates: Vec<FaviconCandidate>,
+    preferred_size: u32,
+    options: &SelectionOptions,
+) -> Vec<FaviconCandidate> {
+    // Remove candidates for the opposite colour scheme
+    candidates.retain(|candidate| {
+        options
+            .color_scheme
+            .rank_key(candidate.color_scheme())
+            .is_some()
+    });
+
+    // Rank by colour scheme and kind of icon first, then prefer scalable candidates
+    // as they can render at any requested size, and finally rank by size
+    candidates.sort_by_cached_key(|candidate| {
+        (
+            options.color_scheme.rank_key(candidate.color_scheme()),
+            candidate.rel_rank(&options.rel_priority),
+            !candidate.is_scalable(),
+            candidate.rank_key(options.size_policy, preferred_size),
+        )
+    });
+
+    candidates
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn candidate(path: &str, sizes: &str, mime_type: Option<&str>) -> FaviconCandidate {
+        FaviconCandidate {
+            url: Url::parse("https://example.com")
+                .unwrap()
+                .join(path)
+                .unwrap(),
+            sizes: parse_sizes(sizes),
+            mime_type: mime_type.map(|mime_type| mime_type.to_owned()),
+            source: CandidateSource::Link,
+            rel: Some("icon".to_owned()),
+            color: None,
+            media: None,
+        }
+    }
+
+    fn with_media(candidate: FaviconCandidate, media: &str) -> FaviconCandidate {
+        FaviconCandidate {
+            media: Some(media.to_owned()),
+            ..candidate
+        }
+    }
+
+    fn ranked_paths_for_scheme(
+        candidates: Vec<FaviconCandidate>,
+        color_scheme: ColorScheme,
+    ) -> Vec<String> {
+        let options = SelectionOptions {
+            color_scheme,
+            ..Default::default()
+        };
+        rank_candidates(candidates, 32, &options)
+            .into_iter()
+            .map(|candidate| candidate.url.path().to_owned())
+            .collect()
+    }
+
+    fn with_rel(candidate: FaviconCandidate, rel: &str) -> FaviconCandidate {
+        FaviconCandidate {
+            rel: Some(rel.to_owned()),
+            ..candidate
+        }
+    }
+
+    fn ranked_paths(
+        candidates: Vec<FaviconCandidate>,
+        preferred_size: u32,
+        size_policy: SizePolicy,
+    ) -> Vec<String> {
+        rank_candidates(
+            candidates,
+            preferred_size,
+            &SelectionOptions {
+                size_policy,
+                ..Default::default()
+            },
+        )
+        .into_iter()
+        .map(|candidate| candidate.url.path().to_owned())
+        .collect()
+    }
+
+    fn fixed_links() -> Vec<FaviconCandidate> {
+        vec![
+            candidate("/16.png", "16x16", None),
+            candidate("/180.png", "180x180", None),
+            candidate("/unknown.png", "", None),
+            candidate("/32.png", "32x32", None),
+            candidate("/64.png", "64x64", None),
+        ]
+    }
+
+    #[test]
+    fn parses_sizes() {
+        assert_eq!(
+            parse_sizes("16x16 32X32  any nonsense 0x"),
+            vec![
+                IconSize::Fixed {
+                    width: 16,
+                    height: 16
+                },
+                IconSize::Fixed {
+                    width: 32,
+                    height: 32
+                },
+                IconSize::Any,
+            ]
+        );
+        assert!(parse_sizes("").is_empty());
+    }
+
+    #[test]
+    fn smallest_at_or_above() {
+        assert_eq!(
+            ranked_paths(fixed_links(), 48, SizePolicy::SmallestAtOrAbove),
+            ["/64.png", "/180.png", "/32.png", "/16.png", "/unknown.png"]
+        );
+        assert_eq!(
+            ranked_paths(fixed_links(), 512, SizePolicy::SmallestAtOrAbove),
+            ["/180.png", "/64.png", "/32.png", "/16.png", "/unknown.png"]
+        );
+    }
+
+    #[test]
+    fn closest() {
+        assert_eq!(
+            ranked_paths(fixed_links(), 40, SizePolicy::Closest),
+            ["/32.png", "/64.png", "/16.png", "/unknown.png", "/180.png"]
+        );
+        // Ties prefer the larger icon
+        assert_eq!(
+            ranked_paths(fixed_links(), 48, SizePolicy::Closest)[0],
+            "/64.png"
+        );
+    }
+
+    #[test]
+    fn largest() {
+        assert_eq!(
+            ranked_paths(fixed_links(), 16, SizePolicy::Largest),
+            ["/180.png", "/64.png", "/32.png", "/16.png", "/unknown.png"]
+        );
+    }
+
+    #[test]
+    fn multiple_declared_sizes() {
+        let links = vec![
+            candidate("/small.ico", "16x16 32x32", None),
+            candidate("/multi.ico", "16x16 32x32 64x64", None),
+        ];
+        assert_eq!(
+            ranked_paths(links, 64, SizePolicy::SmallestAtOrAbove),
+            ["/multi.ico", "/small.ico"]
+        );
+    }
+
+    #[test]
+    fn scalable_ranked_first() {
+        let mut links = fixed_links();
+        links.push(candidate("/any.svg", "any", None));
+        links.push(candidate("/typed.svg", "", Some("image/svg+xml")));
+        for policy in [
+            SizePolicy::SmallestAtOrAbove,
+            SizePolicy::Closest,
+            SizePolicy::Largest,
+        ] {
+            assert_eq!(
+                ranked_paths(links.clone(), 32, policy)[..2],
+                ["/any.svg", "/typed.svg"]
+            );
+        }
+    }
+
+    #[test]
+    fn mask_icons_ranked_last() {
+        let links = vec![
+            with_rel(candidate("/mask.svg", "", None), "mask-icon"),
+            candidate("/16.png", "16x16", None),
+            with_rel(candidate("/apple.png", "180x180", None), "apple-touch-icon"),
+        ];
+        assert_eq!(
+            ranked_paths(links, 256, SizePolicy::SmallestAtOrAbove),
+            ["/apple.png", "/16.png", "/mask.svg"]
+        );
+    }
+
+    #[test]
+    fn rel_priority() {
+        let links = vec![
+            with_rel(candidate("/mask.svg", "", None), "mask-icon"),
+            candidate("/64.png", "64x64", None),
+            with_rel(candidate("/apple.png", "180x180", None), "apple-touch-icon"),
+        ];
+        let options = SelectionOptions {
+            rel_priority: vec![IconRel::MaskIcon, IconRel::Icon],
+            ..Default::default()
+        };
+        let ranked: Vec<_> = rank_candidates(links, 64, &options)
+            .into_iter()
+            .map(|candidate| candidate.url.path().to_owned())
+            .collect();
+        assert_eq!(ranked, ["/mask.svg", "/64.png", "/apple.png"]);
+    }
+
+    #[test]
+    fn parses_rels_and_colors() {
+        assert_eq!(IconRel::from_rel("shortcut icon"), IconRel::Icon);
+        assert_eq!(
+            IconRel::from_rel("apple-touch-icon-precomposed"),
+            IconRel::AppleTouchIcon
+        );
+        assert_eq!(IconRel::from_rel("Mask-Icon"), IconRel::MaskIcon);
+        assert_eq!(
+            parse_hex_color("#5bbad5"),
+            Some(image::Rgb([0x5b, 0xba, 0xd5]))
+        );
+        assert_eq!(parse_hex_color("#fff"), Some(image::Rgb([255, 255, 255])));
+        assert_eq!(parse_hex_color("blue"), None);
+    }
+
+    #[test]
+    fn color_schemes() {
+        let links = || {
+            vec![
+                candidate("/plain.png", "32x32", None),
+                with_media(
+                    candidate("/dark.png", "32x32", None),
+                    "(prefers-color-scheme: dark)",
+                ),
+                with_media(
+                    candidate("/light.png", "32x32", None),
+                    "(prefers-color-scheme:LIGHT)",
+                ),
+            ]
+        };
+        assert_eq!(
+            ranked_paths_for_scheme(links(), ColorScheme::NoPreference),
+            ["/plain.png", "/light.png"]
+        );
+        assert_eq!(
+            ranked_paths_for_scheme(links(), ColorScheme::Light),
+            ["/light.png", "/plain.png"]
+        );
+        assert_eq!(
+            ranked_paths_for_scheme(links(), ColorScheme::Dark),
+            ["/dark.png", "/plain.png"]
+        );
+    }
+}
@@ -0,0 +1,241 @@
+//! Guard against fetching from private and local network addresses
+
+use std::error::Error;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+
+use hyper::client::connect::dns::Name;
+use ipnet::IpNet;
+use reqwest::dns::{Addrs, Resolve, Resolving};
+use thiserror::Error;
+use url::{Host, Url};
+
+/// A request was refused because it would connect to a non-public address
+#[derive(Error, Debug, Clone, Copy)]
+#[error("Refusing to connect to non-public address {0}")]
+pub struct ForbiddenAddress(pub IpAddr);
+
+impl ForbiddenAddress {
+    /// Find the forbidden address that caused an error, anywhere in its source chain
+    pub fn find(err: &(dyn Error + 'static)) -> Option<Self> {
+        find_source(err)
+    }
+}
+
+/// Find an error of a given type anywhere in the source chain of an error
+pub fn find_source<T: Error + Clone + 'static>(err: &(dyn Error + 'static)) -> Option<T> {
+    let mut source = Some(err);
+    while let Some(err) = source {
+        if let Some(found) = err.downcast_ref::<T>() {
+            return Some(found.clone());
+        }
+        source = err.source();
+    }
+    None
+}
+
+/// Rejects loopback, link-local, private and multicast addresses, except those allowed.
+/// Used as the dns resolver of a client, so addresses are checked when they are connected
+/// to, including after redirects and if a host's dns records change between requests.
+#[derive(Debug, Clone, Default)]
+pub struct AddressGuard {
+    /// Networks that may be fetched from even if they are not public
+    pub allowed: Vec<IpNet>,
+}
+
+impl AddressGuard {
+    pub fn check(&self, ip: IpAddr) -> Result<(), ForbiddenAddress> {
+        if is_public(ip) || self.allowed.iter().any(|network| network.contains(&ip)) {
+            Ok(())
+        } else {
+            Err(ForbiddenAddress(ip))
+        }
+    }
+
+    /// Check a url with an ip address host, which is connected to without dns resolution
+    pub fn check_url(&self, url: &Url) -> Result<(), ForbiddenAddress> {
+        match url.host() {
+            Some(Host::Ipv4(ip)) => self.check(ip.into()),
+            Some(Host::Ipv6(ip)) => self.check(ip.into()),
+            _ => Ok(()),
+        }
+    }
+
+    /// Check every address the host of a url resolves to, for urls that were fetched
+    /// elsewhere, such as favicons in a disk cache shared with the cli. Hosts that don't
+    /// resolve can't reach a non-public address.
+    pub async fn check_resolved_url(&self, url: &Url) -> Result<(), ForbiddenAddress> {
+        self.check_url(url)?;
+        let Some(Host::Domain(domain)) = url.host() else {
+            return Ok(());
+        };
+        if let Ok(addrs) = tokio::net::lookup_host((domain, 0)).await {
+            for addr in addrs {
+                self.check(addr.ip())?;
+            }
+        }
+        Ok(())
+    }
+}
+
+impl Resolve for AddressGuard {
+    fn resolve(&self, name: Name) -> Resolving {
+        let guard = self.clone();
+        Box::pin(async move {
+            let addrs: Vec<SocketAddr> =
+                tokio::net::lookup_host((name.as_str(), 0)).await?.collect();
+
+            // Reject hosts with any forbidden address, rather than only connecting to the others
+            for addr in &addrs {
+                guard.check(addr.ip())?;
+            }
+
+            Ok(Box::new(addrs.into_iter()) as Addrs)
+        })
+    }
+}
+
+/// Whether an address is reachable on the public internet
+fn is_public(ip: IpAddr) -> bool {
+    match ip {
+        IpAddr::V4(ip) => {
+            let [a, b, c, _] = ip.octets();
+            let this_network = a == 0;
+            let shared = a == 100 && b & 0xc0 == 64;
+            let protocol_assignments = a == 192 && b == 0 && c == 0;
+            let benchmarking = a == 198 && b & 0xfe == 18;
+            let reserved = a >= 240;
+            !(ip.is_loopback()
+                || ip.is_private()
+                || ip.is_link_local()
+                || ip.is_multicast()
+                || this_network
+                || shared
+                || protocol_assignments
+                || benchmarking
+                || reserved)
+        }
+        IpAddr::V6(ip) => match embedded_ipv4(ip) {
+            Some(ip) => is_public(ip.into()),
+            None => {
+                let unique_local = ip.segments()[0] & 0xfe00 == 0xfc00;
+                let link_local = ip.segments()[0] & 0xffc0 == 0xfe80;
+                !(ip.is_loopback()
+                    || ip.is_multicast()
+                    || ip.is_unspecified()
+                    || unique_local
+                    || link_local)
+            }
+        },
+    }
+}
+
+/// The ipv4 address that an ipv6 address translates to, for ipv4-mapped (`::ffff:0:0/96`),
+/// ipv4-compatible (`::/96`), NAT64 (`64:ff9b::/96`) and 6to4 (`2002::/16`) addresses
+fn embedded_ipv4(ip: Ipv6Addr) -> Option<Ipv4Addr> {
+    let segments = ip.segments();
+    let last_32_bits = Ipv4Addr::from(u128::from(ip) as u32);
+    match segments {
+        [0, 0, 0, 0, 0, 0xffff, _, _] => Some(last_32_bits),
+        // Leave the loopback and unspecified addresses to the ipv6 checks
+        [0, 0, 0, 0, 0, 0, _, _] if !ip.is_loopback() && !ip.is_unspecified() => Some(last_32_bits),
+        [0x64, 0xff9b, 0, 0, 0, 0, _, _] => Some(last_32_bits),
+        [0x2002, high, low, ..] => Some(Ipv4Addr::from((u32::from(high) << 16) | u32::from(low))),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::favicon_image::fetch::FetchOptions;
+
+    #[test]
+    fn rejects_non_public_addresses() {
+        let guard = AddressGuard::default();
+        for ip in [
+            "127.0.0.1",
+            "10.1.2.3",
+            "172.16.0.1",
+            "192.168.1.1",
+            "169.254.169.254",
+            "224.0.0.1",
+            "0.0.0.0",
+            "0.1.2.3",
+            "100.64.0.1",
+            "100.100.100.200",
+            "192.0.0.8",
+            "198.18.0.1",
+            "198.19.255.254",
+            "240.0.0.1",
+            "255.255.255.255",
+            "::",
+            "::1",
+            "fd00::1",
+            "fe80::1",
+            "ff02::1",
+            "::ffff:127.0.0.1",
+            "::10.0.0.1",
+            "64:ff9b::a9fe:a9fe",
+            "2002:c0a8:101::1",
+        ] {
+            assert!(guard.check(ip.parse().unwrap()).is_err(), "{ip}");
+        }
+        for ip in [
+            "93.184.216.34",
+            "100.128.0.1",
+            "198.20.0.1",
+            "2606:2800:220:1::1",
+            "64:ff9b::5db8:d822",
+            "2002:5db8:d822::1",
+        ] {
+            assert!(guard.check(ip.parse().unwrap()).is_ok(), "{ip}");
+        }
+    }
+
+    #[test]
+    fn allows_allowlisted_networks() {
+        let guard = AddressGuard {
+            allowed: vec!["10.0.0.0/8".parse().unwrap()],
+        };
+        assert!(guard.check("10.1.2.3".parse().unwrap()).is_ok());
+        assert!(guard.check("192.168.1.1".parse().unwrap()).is_err());
+    }
+
+    #[test]
+    fn rejects_ip_address_urls() {
+        let guard = AddressGuard::default();
+        assert!(guard
+            .check_url(&Url::parse("http://169.254.169.254/latest").unwrap())
+            .is_err());
+        assert!(guard
+            .check_url(&Url::parse("http://[::1]:3000/").unwrap())
+            .is_err());
+        assert!(guard
+            .check_url(&Url::parse("https://example.com/").unwrap())
+            .is_ok());
+    }
+
+    #[tokio::test]
+    async fn rejects_resolved_addresses() {
+        let client = FetchOptions::default().build_client();
+        let err = client
+            .get("http://localhost:9/favicon.ico")
+            .send()
+            .await
+            .unwrap_err();
+        assert!(ForbiddenAddress::find(&err).is_some());
+    }
+
+    #[tokio::test]
+    async fn rejects_urls_resolving_to_non_public_addresses() {
+        let guard = AddressGuard::default();
+        assert!(guard
+            .check_resolved_url(&Url::parse("http://localhost/favicon.ico").unwrap())
+            .await
+            .is_err());
+        assert!(guard
+            .check_resolved_url(&Url::parse("data:image/png;base64,").unwrap())
+            .await
+            .is_ok());
+    }
+}
@@ -0,0 +1,173 @@
+//! Rules controlling which hosts the server will fetch favicons for
+
+use std::str::FromStr;
+
+use regex::Regex;
+use thiserror::Error;
+use url::Url;
+
+/// A request was refused because its host is not allowed
+#[derive(Error, Debug, Clone)]
+#[error("Fetching favicons for host {0:?} is not allowed")]
+pub struct HostNotAllowed(pub String);
+
+/// A pattern matching target hosts, parsed from an exact host (`example.com`),
+/// a wildcard matching its subdomains (`*.example.com`) or a regex (`/\.example\.com$/`)
+#[derive(Debug, Clone)]
+pub enum HostRule {
+    Exact(String),
+    Subdomains(String),
+    Regex(Regex),
+}
+
+impl FromStr for HostRule {
+    type Err = regex::Error;
+
+    fn from_str(rule: &str) -> Result<Self, Self::Err> {
+        if rule.len() > 1 && rule.starts_with('/') && rule.ends_with('/') {
+            // Remove the first and last slash
+            Ok(Self::Regex(Regex::new(&rule[1..rule.len() - 1])?))
+        } else if let Some(domain) = rule.strip_prefix("*.") {
+            Ok(Self::Subdomains(domain.to_ascii_lowercase()))
+        } else {
+            Ok(Self::Exact(rule.to_ascii_lowercase()))
+        }
+    }
+}
+
+impl HostRule {
+    pub fn matches(&self, host: &str) -> bool {
+        // Fully qualified hosts with a trailing dot resolve to the same address
+        let host = host.strip_suffix('.').unwrap_or(host);
+        match self {
+            Self::Exact(exact) => host.eq_ignore_ascii_case(exact),
+            Self::Subdomains(domain) => host
+                .to_ascii_lowercase()
+                .strip_suffix(domain.as_str())
+                .is_some_and(|subdomain| subdomain.len() > 1 && subdomain.ends_with('.')),
+            Self::Regex(re) => re.is_match(host),
+        }
+    }
+}
+
+/// Hosts the server may fetch for. Denied hosts are never fetched, and if any hosts
+/// are allowed then only pages on those hosts are scraped, while their icons, manifests
+/// and redirects may be on any host that isn't denied.
+#[derive(Debug, Clone, Default)]
+pub struct HostRules {
+    pub allow: Vec<HostRule>,
+    pub deny: Vec<HostRule>,
+}
+
+impl HostRules {
+    pub fn is_allowed(&self, host: &str) -> bool {
+        !self.is_denied(host)
+            && (self.allow.is_empty() || self.allow.iter().any(|rule| rule.matches(host)))
+    }
+
+    pub fn is_denied(&self, host: &str) -> bool {
+        self.deny.iter().any(|rule| rule.matches(host))
+    }
+
+    /// Check the host of any url fetched against the deny rules, allowing urls without a
+    /// host such as data urls
+    pub fn check_url(&self, url: &Url) -> Result<(), HostNotAllowed> {
+        match url.host_str() {
+            Some(host) if self.is_denied(host) => Err(HostNotAllowed(host.to_owned())),
+            _ => Ok(()),
+        }
+    }
+
+    /// Check the host of a page scraped for icons against both the allow and deny rules
+    pub fn check_page_url(&self, url: &Url) -> Result<(), HostNotAllowed> {
+        match url.host_str() {
+            Some(host) if !self.is_allowed(host) => Err(HostNotAllowed(host.to_owned())),
+            _ => Ok(()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn rules(rules: &[&str]) -> Vec<HostRule> {
+        rules.iter().map(|rule| rule.parse().unwrap()).collect()
+    }
+
+    #[test]
+    fn matches_rules() {
+        let exact: HostRule = "Example.com".parse().unwrap();
+        assert!(exact.matches("example.com"));
+        assert!(!exact.matches("www.example.com"));
+
+        let subdomains: HostRule = "*.example.com".parse().unwrap();
+        assert!(subdomains.matches("www.example.com"));
+        assert!(subdomains.matches("a.b.example.com"));
+        assert!(!subdomains.matches("example.com"));
+        assert!(!subdomains.matches("badexample.com"));
+
+        let regex: HostRule = r"/^(www\.)?example\.(com|org)$/".parse().unwrap();
+        assert!(regex.matches("www.example.org"));
+        assert!(!regex.matches("example.net"));
+    }
+
+    #[test]
+    fn deny_overrides_allow() {
+        let host_rules = HostRules {
+            allow: rules(&["*.example.com"]),
+            deny: rules(&["internal.example.com"]),
+        };
+        assert!(host_rules.is_allowed("www.example.com"));
+        assert!(!host_rules.is_allowed("internal.example.com"));
+        assert!(!host_rules.is_allowed("example.org"));
+    }
+
+    #[test]
+    fn matches_hosts_with_a_trailing_dot() {
+        let host_rules = HostRules {
+            allow: Vec::new(),
+            deny: rules(&[
+                "internal.example.com",
+                "*.corp.com",
+                r"/^admin\.example\.org$/",
+            ]),
+        };
+        for url in [
+            "http://internal.example.com./",
+            "http://www.corp.com./",
+            "http://admin.example.org./",
+        ] {
+            let url = Url::parse(url).unwrap();
+            assert!(host_rules.check_url(&url).is_err(), "{url}");
+        }
+    }
+
+    #[test]
+    fn checks_urls_with_hosts() {
+        let host_rules = HostRules {
+            allow: rules(&["example.com"]),
+            deny: Vec::new(),
+        };
+        let check = |url: &str| host_rules.check_page_url(&Url::parse(url).unwrap());
+        assert!(check("https://example.com/").is_ok());
+        assert!(check("https://example.org/").is_err());
+        assert!(check("data:text/html,").is_ok());
+    }
+
+    #[test]
+    fn only_checks_pages_against_allow_rules() {
+        let host_rules = HostRules {
+            allow: rules(&["example.com"]),
+            deny: rules(&["tracker.example.net"]),
+        };
+        let check = |url: &str| host_rules.check_url(&Url::parse(url).unwrap());
+        assert!(check("https://assets.example.org/favicon.png").is_ok());
+        assert!(check("https://tracker.example.net/favicon.png").is_err());
+    }
+
+    #[test]
+    fn allows_everything_without_rules() {
+        assert!(HostRules::default().is_allowed("example.org"));
+    }
+}
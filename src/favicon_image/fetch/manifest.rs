@@ -0,0 +1,93 @@
+//! Methods for reading the icons declared in a web app manifest
+
+use reqwest::Client;
+use serde::Deserialize;
+use url::Url;
+
+use super::scrape::ScrapeError;
+use super::{read_body_limited, send_request, FetchOptions};
+
+#[derive(Debug, Deserialize)]
+struct Manifest {
+    #[serde(default)]
+    icons: Vec<ManifestIcon>,
+}
+
+/// An entry of the `icons` array of a web app manifest
+#[derive(Debug, Clone, Deserialize)]
+pub struct ManifestIcon {
+    pub src: String,
+    pub sizes: Option<String>,
+    #[serde(rename = "type")]
+    pub mime_type: Option<String>,
+    pub purpose: Option<String>,
+}
+
+impl ManifestIcon {
+    /// Monochrome icons are intended as masks, so aren't suitable as a favicon. Icons
+    /// that also have another purpose can still be used.
+    pub fn is_monochrome(&self) -> bool {
+        let mut purposes = self
+            .purpose
+            .iter()
+            .flat_map(|purpose| purpose.split_ascii_whitespace())
+            .peekable();
+        purposes.peek().is_some()
+            && purposes.all(|purpose| purpose.eq_ignore_ascii_case("monochrome"))
+    }
+}
+
+/// Fetch a web app manifest and return the icons it declares
+pub async fn fetch_manifest_icons(
+    client: &Client,
+    manifest_url: &Url,
+    fetch_options: &FetchOptions,
+) -> Result<Vec<ManifestIcon>, ScrapeError> {
+    let res = send_request::<ScrapeError>(client, manifest_url, fetch_options)
+        .await?
+        .error_for_status()?;
+    let body = read_body_limited::<ScrapeError>(res, fetch_options.max_html_bytes).await?;
+
+    let manifest: Manifest = serde_json::from_slice(&body)?;
+    Ok(manifest.icons)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn icon(purpose: Option<&str>) -> ManifestIcon {
+        ManifestIcon {
+            src: "/icon.png".to_owned(),
+            sizes: None,
+            mime_type: None,
+            purpose: purpose.map(str::to_owned),
+        }
+    }
+
+    #[test]
+    fn detects_monochrome_icons() {
+        assert!(icon(Some("monochrome")).is_monochrome());
+        assert!(icon(Some(" MONOCHROME ")).is_monochrome());
+
+        assert!(!icon(None).is_monochrome());
+        assert!(!icon(Some("")).is_monochrome());
+        assert!(!icon(Some("any")).is_monochrome());
+        assert!(!icon(Some("maskable")).is_monochrome());
+        assert!(!icon(Some("monochrome any")).is_monochrome());
+    }
+
+    #[test]
+    fn reads_manifest_icons() {
+        let manifest: Manifest = serde_json::from_str(
+            r#"{"name": "Example", "icons": [{"src": "/a.png", "sizes": "192x192", "type": "image/png", "purpose": "monochrome"}]}"#,
+        )
+        .unwrap();
+        assert_eq!(manifest.icons.len(), 1);
+        assert_eq!(manifest.icons[0].mime_type.as_deref(), Some("image/png"));
+        assert!(manifest.icons[0].is_monochrome());
+
+        let manifest: Manifest = serde_json::from_str(r#"{"name": "Example"}"#).unwrap();
+        assert!(manifest.icons.is_empty());
+    }
+}
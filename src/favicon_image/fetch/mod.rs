@@ -1,25 +1,59 @@
 //! Methods for fetching a favicon image from a url and interpreting its format
 
+mod candidate;
+mod guard;
+mod host_rules;
+mod manifest;
 mod scrape;
+mod svg_images;
+mod validators;
 
+use data_url::DataUrl;
 use reqwest::{
-    header::{CONTENT_TYPE, USER_AGENT},
-    Client,
+    header::{HeaderMap, HeaderName, CONTENT_TYPE, USER_AGENT},
+    redirect::Policy,
+    Client, Response, StatusCode,
 };
-use std::io;
+use std::{fmt, io, sync::Arc, time::Duration};
 use thiserror::Error;
 use url::Url;
 
-use scrape::{scrape_link_tags, ScrapeError};
+use super::animation::{decode_frames, first_frame};
+use super::ico::select_ico_entry;
+use super::svg::{has_svg_extension, is_svg_mime_type, sniff_svg, svg_text, SvgError};
+use crate::disk_cache::{unix_now, CacheMetadata, DiskCache};
+pub use candidate::{ColorScheme, FaviconCandidate, IconRel, SelectionOptions, SizePolicy};
+use guard::find_source;
+pub use guard::{AddressGuard, ForbiddenAddress};
+#[cfg(feature = "server")]
+pub use host_rules::HostRule;
+pub use host_rules::{HostNotAllowed, HostRules};
+pub use scrape::{scrape_candidates, ScrapeError};
+use svg_images::fetch_svg_images;
+pub use validators::{FaviconValidators, Validators};
 pub const BOT_USER_AGENT: &str = "Mozilla/5.0 (Windows NT 6.1) AppleWebKit/537.36 (KHTML, like Gecko) Chrome/41.0.2228.0 Safari/537.36";
 
+pub const DEFAULT_CONNECT_TIMEOUT: Duration = Duration::from_secs(5);
+pub const DEFAULT_TIMEOUT: Duration = Duration::from_secs(10);
+pub const DEFAULT_TOTAL_TIMEOUT: Duration = Duration::from_secs(30);
+pub const DEFAULT_MAX_HTML_BYTES: usize = 2 * 1024 * 1024;
+pub const DEFAULT_MAX_IMAGE_BYTES: usize = 5 * 1024 * 1024;
+pub const DEFAULT_RETRIES: u32 = 2;
+const RETRY_BACKOFF: Duration = Duration::from_millis(250);
+
+/// Most favicon candidates tried before giving up
+const MAX_CANDIDATE_ATTEMPTS: usize = 8;
+
 #[derive(Error, Debug)]
 pub enum FetchFaviconError {
     #[error(transparent)]
     Scrape(#[from] ScrapeError),
 
     #[error(transparent)]
-    Network(#[from] reqwest::Error),
+    Network(reqwest::Error),
+
+    #[error(transparent)]
+    ForbiddenAddress(#[from] ForbiddenAddress),
 
     #[error(transparent)]
     TokioError(#[from] tokio::task::JoinError),
@@ -31,41 +65,523 @@ pub enum FetchFaviconError {
     #[error("Provided URL is not a valid url")]
     InvalidUrl,
 
+    #[error(transparent)]
+    HostNotAllowed(#[from] HostNotAllowed),
+
+    /// An error from a fetch shared by concurrent requests
+    #[cfg(feature = "server")]
+    #[error(transparent)]
+    Shared(Arc<FetchFaviconError>),
+
     #[error("Cannot decode the image type")]
     CannotDecode,
+
+    #[error("Data url is not valid")]
+    InvalidDataUrl,
+
+    #[error(transparent)]
+    Svg(#[from] SvgError),
+
+    #[error("No favicon candidate could be fetched: {0}")]
+    AllCandidatesFailed(FailedAttempts),
+
+    #[error("Fetching the favicon took longer than {} seconds", .0.as_secs())]
+    TimedOut(Duration),
+
+    #[error(transparent)]
+    BodyTooLarge(#[from] BodyTooLarge),
+}
+
+impl From<reqwest::Error> for FetchFaviconError {
+    fn from(err: reqwest::Error) -> Self {
+        if let Some(forbidden) = ForbiddenAddress::find(&err) {
+            return Self::ForbiddenAddress(forbidden);
+        }
+        match find_source::<HostNotAllowed>(&err) {
+            Some(not_allowed) => Self::HostNotAllowed(not_allowed),
+            None => Self::Network(err),
+        }
+    }
+}
+
+#[cfg(feature = "server")]
+impl FetchFaviconError {
+    /// The original error, looking through any copies shared between requests
+    pub fn inner(&self) -> &Self {
+        match self {
+            Self::Shared(inner) => inner.inner(),
+            err => err,
+        }
+    }
+}
+
+/// A response body was larger than allowed
+#[derive(Error, Debug)]
+#[error("Response body is larger than {0} bytes")]
+pub struct BodyTooLarge(pub usize);
+
+/// Options controlling how favicons are fetched
+#[derive(Debug, Clone)]
+pub struct FetchOptions {
+    /// Allow svg favicons to load images over http from the same origin as the svg.
+    /// Images in data urls are always allowed, and local files never are.
+    pub svg_remote_images: bool,
+
+    /// Only decode the first frame of animated favicons
+    pub static_only: bool,
+
+    /// Time allowed to connect to a server
+    pub connect_timeout: Duration,
+
+    /// Time allowed for each request, from connecting until the body is read
+    pub timeout: Duration,
+
+    /// Time allowed to fetch a favicon, including every request and retry
+    pub total_timeout: Duration,
+
+    /// Largest html page or manifest that will be read
+    pub max_html_bytes: usize,
+
+    /// Largest image that will be read
+    pub max_image_bytes: usize,
+
+    /// Times to retry requests that fail to connect or return a server error
+    pub retries: u32,
+
+    /// Refuse to fetch from private and local network addresses, if set
+    pub address_guard: Option<AddressGuard>,
+
+    /// Hosts that may be fetched from, including after redirects
+    pub host_rules: HostRules,
+
+    /// Store fetched favicons on disk, and reuse them instead of fetching again
+    pub disk_cache: Option<DiskCache>,
+}
+
+impl Default for FetchOptions {
+    fn default() -> Self {
+        Self {
+            svg_remote_images: false,
+            static_only: false,
+            connect_timeout: DEFAULT_CONNECT_TIMEOUT,
+            timeout: DEFAULT_TIMEOUT,
+            total_timeout: DEFAULT_TOTAL_TIMEOUT,
+            max_html_bytes: DEFAULT_MAX_HTML_BYTES,
+            max_image_bytes: DEFAULT_MAX_IMAGE_BYTES,
+            retries: DEFAULT_RETRIES,
+            address_guard: Some(AddressGuard::default()),
+            host_rules: HostRules::default(),
+            disk_cache: None,
+        }
+    }
+}
+
+impl FetchOptions {
+    /// Build a http client that applies the timeouts, address guard and host rules of these options
+    pub fn build_client(&self) -> Client {
+        let builder = Client::builder()
+            .connect_timeout(self.connect_timeout)
+            .timeout(self.timeout)
+            .redirect(self.redirect_policy());
+
+        // Proxies would resolve hosts themselves, bypassing the guard
+        let builder = match &self.address_guard {
+            Some(guard) => builder.dns_resolver(Arc::new(guard.clone())).no_proxy(),
+            None => builder,
+        };
+
+        builder.build().expect("Http client should build")
+    }
+
+    /// Redirect policy that checks the urls redirected to, otherwise following the default policy
+    fn redirect_policy(&self) -> Policy {
+        let (guard, host_rules) = (self.address_guard.clone(), self.host_rules.clone());
+        Policy::custom(move |attempt| {
+            if let Some(Err(forbidden)) = guard.as_ref().map(|guard| guard.check_url(attempt.url()))
+            {
+                return attempt.error(forbidden);
+            }
+            match host_rules.check_url(attempt.url()) {
+                Ok(()) => Policy::default().redirect(attempt),
+                Err(not_allowed) => attempt.error(not_allowed),
+            }
+        })
+    }
+}
+
+/// The original response for a favicon candidate
+struct FetchedIcon {
+    content_type: Option<String>,
+    validators: Validators,
+    body: Vec<u8>,
+}
+
+/// A favicon candidate that could not be fetched or decoded
+#[derive(Debug)]
+pub struct FailedAttempt {
+    pub url: Url,
+    pub error: FetchFaviconError,
+}
+
+/// Every candidate that was tried while fetching a favicon, in order
+#[derive(Debug)]
+pub struct FailedAttempts(pub Vec<FailedAttempt>);
+
+impl fmt::Display for FailedAttempts {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for (i, attempt) in self.0.iter().enumerate() {
+            if i > 0 {
+                write!(f, "; ")?;
+            }
+            // Data urls can be very long, so only show their start
+            match attempt.url.scheme() {
+                "data" => write!(f, "{:.32}... ({})", attempt.url.as_str(), attempt.error)?,
+                _ => write!(f, "{} ({})", attempt.url, attempt.error)?,
+            }
+        }
+        Ok(())
+    }
+}
+
+/// A fetched favicon, or confirmation that a previously fetched favicon is unchanged
+#[derive(Debug, Clone)]
+// Only the server keeps favicons in memory to revalidate later
+#[cfg_attr(not(feature = "server"), allow(dead_code))]
+pub enum FetchedFavicon {
+    Modified(super::FaviconImage, FaviconValidators),
+    NotModified(FaviconValidators),
+}
+
+/// The response of fetching a favicon from its site, including the original image
+enum SiteResponse {
+    Modified(super::FaviconImage, FaviconValidators, FetchedIcon),
+    NotModified(FaviconValidators),
 }
 
 /// Fetch the favicon for a given url
 impl super::FaviconImage {
+    /// Fetch the favicon for a given url
     pub async fn fetch_for_url(
         client: &Client,
         target_url: &Url,
         size: u32,
+        options: &SelectionOptions,
+        fetch_options: &FetchOptions,
     ) -> Result<Self, FetchFaviconError> {
-        // Determine favicon url
-        let image_url = scrape_link_tags(client, target_url, size)
+        Self::fetch_with_validators(client, target_url, size, options, fetch_options)
             .await
-            .unwrap_or_else(|_| target_url.join("/favicon.ico").unwrap());
+            .map(|(image, _)| image)
+    }
 
-        // Fetch the image
-        let res = client
-            .get(image_url)
-            .header(USER_AGENT, BOT_USER_AGENT)
-            .send()
-            .await?;
-
-        // Render SVGs
-        if res
-            .headers()
-            .get(CONTENT_TYPE)
-            .is_some_and(|content_type| content_type == "image/svg+xml")
-        {
-            let svg = res.text().await?;
-            return Ok(Self::from_svg_str(svg, size));
+    /// Fetch the favicon for a given url, with the validators needed to revalidate it later
+    pub async fn fetch_with_validators(
+        client: &Client,
+        target_url: &Url,
+        size: u32,
+        options: &SelectionOptions,
+        fetch_options: &FetchOptions,
+    ) -> Result<(Self, FaviconValidators), FetchFaviconError> {
+        let fetch =
+            Self::fetch_or_revalidate(client, target_url, size, options, fetch_options, None);
+        match with_deadline(fetch_options, fetch).await? {
+            FetchedFavicon::Modified(image, validators) => Ok((image, validators)),
+            FetchedFavicon::NotModified(_) => {
+                unreachable!("Favicons fetched without validators are always modified")
+            }
+        }
+    }
+
+    /// Check whether a previously fetched favicon has changed, requesting the page and
+    /// favicon conditionally. An unchanged favicon is reported as not modified, so the
+    /// previous copy can be kept.
+    #[cfg(feature = "server")]
+    pub async fn revalidate(
+        client: &Client,
+        target_url: &Url,
+        size: u32,
+        options: &SelectionOptions,
+        fetch_options: &FetchOptions,
+        validators: &FaviconValidators,
+    ) -> Result<FetchedFavicon, FetchFaviconError> {
+        let fetch = Self::fetch_or_revalidate(
+            client,
+            target_url,
+            size,
+            options,
+            fetch_options,
+            Some(validators),
+        );
+        with_deadline(fetch_options, fetch).await
+    }
+
+    async fn fetch_or_revalidate(
+        client: &Client,
+        target_url: &Url,
+        size: u32,
+        options: &SelectionOptions,
+        fetch_options: &FetchOptions,
+        validators: Option<&FaviconValidators>,
+    ) -> Result<FetchedFavicon, FetchFaviconError> {
+        let cached = match &fetch_options.disk_cache {
+            Some(cache) => read_disk_cache(cache, target_url, options, size)
+                .await
+                .map(|(metadata, body)| (cache, metadata, body)),
+            None => None,
+        };
+
+        // The disk cache may be shared with fetches made without the address guard
+        if let (Some(guard), Some((_, metadata, _))) = (&fetch_options.address_guard, &cached) {
+            guard.check_resolved_url(target_url).await?;
+            guard.check_resolved_url(&metadata.candidate.url).await?;
+        }
+
+        // Use a favicon cached on disk until it expires, unless the caller is revalidating its
+        // own copy, which has to be checked against the site itself
+        if let (Some((cache, metadata, body)), None) = (&cached, validators) {
+            if !cache.is_expired(metadata) {
+                if let Ok(image) =
+                    Self::decode_cached(client, metadata, body.clone(), size, fetch_options).await
+                {
+                    return Ok(FetchedFavicon::Modified(image, metadata.validators()));
+                }
+            }
+        }
+
+        // Revalidate an expired favicon from the disk cache, unless the caller has its own copy
+        let cached_validators = cached
+            .as_ref()
+            .map(|(_, metadata, _)| metadata.validators());
+        let response = Self::fetch_from_site(
+            client,
+            target_url,
+            size,
+            options,
+            fetch_options,
+            validators.or(cached_validators.as_ref()),
+        )
+        .await?;
+
+        match (response, validators, cached) {
+            (SiteResponse::Modified(image, new_validators, icon), _, _) => {
+                if let Some(cache) = &fetch_options.disk_cache {
+                    let metadata = CacheMetadata {
+                        target_url: target_url.clone(),
+                        selection: options.clone(),
+                        size,
+                        candidate: new_validators.candidate.clone(),
+                        content_type: icon.content_type,
+                        validators: new_validators.icon.clone(),
+                        page_validators: new_validators.page.clone(),
+                        fetched_at: unix_now(),
+                    };
+                    store_in_disk_cache(cache, metadata, icon.body).await;
+                }
+                Ok(FetchedFavicon::Modified(image, new_validators))
+            }
+
+            // The favicon on disk is unchanged, so keep it for longer
+            (SiteResponse::NotModified(new_validators), None, Some((cache, metadata, body))) => {
+                let metadata = CacheMetadata {
+                    page_validators: new_validators.page.clone(),
+                    fetched_at: unix_now(),
+                    ..metadata
+                };
+                let image =
+                    Self::decode_cached(client, &metadata, body.clone(), size, fetch_options)
+                        .await?;
+                store_in_disk_cache(cache, metadata, body).await;
+                Ok(FetchedFavicon::Modified(image, new_validators))
+            }
+
+            // The caller's favicon is unchanged
+            (SiteResponse::NotModified(new_validators), _, _) => {
+                Ok(FetchedFavicon::NotModified(new_validators))
+            }
+        }
+    }
+
+    /// Scrape a site and fetch its favicon, requesting the page and favicon conditionally
+    /// if validators are provided
+    async fn fetch_from_site(
+        client: &Client,
+        target_url: &Url,
+        size: u32,
+        options: &SelectionOptions,
+        fetch_options: &FetchOptions,
+        validators: Option<&FaviconValidators>,
+    ) -> Result<SiteResponse, FetchFaviconError> {
+        let no_validators = Validators::default();
+        let page_validators = validators.map_or(&no_validators, |validators| &validators.page);
+
+        // Determine favicon candidates, reusing the previous candidate if the page is unchanged
+        // or fails to load while revalidating, unless the site itself cannot be fetched from
+        let scraped = scrape_candidates(
+            client,
+            target_url,
+            size,
+            options,
+            fetch_options,
+            page_validators,
+        )
+        .await;
+        let (candidates, page) = match (scraped, validators) {
+            (Ok(scraped), Some(validators)) if !scraped.status.is_success() => {
+                (vec![validators.candidate.clone()], validators.page.clone())
+            }
+            (Ok(scraped), _) => (scraped.candidates, scraped.validators),
+            (Err(ScrapeError::ForbiddenAddress(forbidden)), _) => return Err(forbidden.into()),
+            (Err(ScrapeError::HostNotAllowed(not_allowed)), _) => return Err(not_allowed.into()),
+            (Err(_), Some(validators)) => {
+                (vec![validators.candidate.clone()], validators.page.clone())
+            }
+            (Err(_), None) => (
+                vec![FaviconCandidate::default_for(target_url)],
+                Validators::default(),
+            ),
+        };
+
+        // Try each candidate in order until one can be decoded, giving up after a few so
+        // pages with many icons can't cause endless requests
+        let mut failed = Vec::new();
+        for candidate in candidates.into_iter().take(MAX_CANDIDATE_ATTEMPTS) {
+            let icon_validators = validators
+                .filter(|validators| validators.candidate.url == candidate.url)
+                .map_or(&no_validators, |validators| &validators.icon);
+            match Self::fetch_candidate(client, &candidate, size, fetch_options, icon_validators)
+                .await
+            {
+                Ok(Some((image, icon))) => {
+                    let validators = FaviconValidators {
+                        page,
+                        candidate,
+                        icon: icon.validators.clone(),
+                    };
+                    return Ok(SiteResponse::Modified(image, validators, icon));
+                }
+                Ok(None) => {
+                    return Ok(SiteResponse::NotModified(FaviconValidators {
+                        page,
+                        candidate,
+                        icon: icon_validators.clone(),
+                    }))
+                }
+                Err(error) => failed.push(FailedAttempt {
+                    url: candidate.url,
+                    error,
+                }),
+            }
+        }
+
+        Err(FetchFaviconError::AllCandidatesFailed(FailedAttempts(
+            failed,
+        )))
+    }
+
+    /// Decode the original image of a favicon cached on disk
+    async fn decode_cached(
+        client: &Client,
+        metadata: &CacheMetadata,
+        body: Vec<u8>,
+        size: u32,
+        fetch_options: &FetchOptions,
+    ) -> Result<Self, FetchFaviconError> {
+        Self::decode(
+            client,
+            body,
+            metadata.content_type.as_deref(),
+            &metadata.candidate,
+            size,
+            fetch_options,
+        )
+        .await
+    }
+
+    /// Fetch and decode the image of a specific favicon candidate, returning the
+    /// original response alongside the decoded image, or `None` if the image is
+    /// unchanged since the validators were seen
+    async fn fetch_candidate(
+        client: &Client,
+        candidate: &FaviconCandidate,
+        size: u32,
+        fetch_options: &FetchOptions,
+        validators: &Validators,
+    ) -> Result<Option<(Self, FetchedIcon)>, FetchFaviconError> {
+        // Read inline images from data urls, otherwise fetch the image
+        let icon = if candidate.url.scheme() == "data" {
+            let (content_type, body) = decode_data_url(&candidate.url)?;
+            FetchedIcon {
+                content_type,
+                validators: Validators::default(),
+                body,
+            }
+        } else {
+            let Some(res) = send_conditional_request::<FetchFaviconError>(
+                client,
+                &candidate.url,
+                fetch_options,
+                validators,
+            )
+            .await?
+            else {
+                return Ok(None);
+            };
+            let res = res.error_for_status()?;
+            FetchedIcon {
+                content_type: header_string(res.headers(), CONTENT_TYPE),
+                validators: Validators::from_headers(res.headers()),
+                body: read_body_limited::<FetchFaviconError>(res, fetch_options.max_image_bytes)
+                    .await?,
+            }
+        };
+
+        let image = Self::decode(
+            client,
+            icon.body.clone(),
+            icon.content_type.as_deref(),
+            candidate,
+            size,
+            fetch_options,
+        )
+        .await?;
+        Ok(Some((image, icon)))
+    }
+
+    /// Decode the image data of a favicon candidate
+    async fn decode(
+        client: &Client,
+        body: Vec<u8>,
+        content_type: Option<&str>,
+        candidate: &FaviconCandidate,
+        size: u32,
+        fetch_options: &FetchOptions,
+    ) -> Result<Self, FetchFaviconError> {
+        // Read SVGs, detected by their declared type, file extension or content. Mask icons
+        // are always svgs. Decompressing svgz can be slow, so it happens on a blocking thread.
+        let mask_icon = candidate.icon_rel() == IconRel::MaskIcon;
+        let declared_svg = mask_icon
+            || content_type.is_some_and(is_svg_mime_type)
+            || candidate.mime_type.as_deref().is_some_and(is_svg_mime_type)
+            || has_svg_extension(&candidate.url);
+        let max_bytes = fetch_options.max_image_bytes;
+        let (body, svg) = tokio::task::spawn_blocking(move || {
+            let svg = match declared_svg {
+                true => svg_text(&body, max_bytes).map(Some),
+                false => sniff_svg(&body, max_bytes),
+            };
+            (body, svg)
+        })
+        .await?;
+
+        if let Some(svg) = svg? {
+            let image = Self::render_svg(client, svg, &candidate.url, size, fetch_options).await?;
+
+            // Render mask icons tinted with their colour
+            return Ok(match mask_icon {
+                true => image.tint(candidate.mask_color()),
+                false => image,
+            });
         }
 
-        // Get HTTP response body
-        let body = res.bytes().await?;
         let cursor = io::Cursor::new(body);
 
         // Create reader and attempt to guess image format
@@ -75,8 +591,26 @@ impl super::FaviconImage {
 
         // Decode the image!
         let image_format = image_reader.format();
-        let image_data = tokio::task::spawn_blocking(move || {
-            match image_format {
+        let animate = !fetch_options.static_only;
+        let (image_data, frames) = tokio::task::spawn_blocking(move || {
+            // Keep every frame of animated images
+            let image_reader = match image_format {
+                Some(format) if animate => {
+                    let data = image_reader.into_inner().into_inner();
+                    if let Some(mut frames) = decode_frames(&data, format) {
+                        let image_data = first_frame(&frames);
+                        // Reuse the frame of still images rather than decoding them again
+                        if frames.len() == 1 {
+                            frames.clear();
+                        }
+                        return Ok((image_data, frames));
+                    }
+                    image::io::Reader::with_format(io::Cursor::new(data), format)
+                }
+                _ => image_reader,
+            };
+
+            let image_data = match image_format {
                 // Use `webp` crate to decode WebPs
                 Some(image::ImageFormat::WebP) => {
                     let data = image_reader.into_inner().into_inner();
@@ -87,18 +621,231 @@ impl super::FaviconImage {
                         .map(|webp| webp.to_image())
                 }
 
+                // Decode the ico entry closest to the requested size
+                Some(image::ImageFormat::Ico) => {
+                    let data = image_reader.into_inner().into_inner();
+                    let data = select_ico_entry(&data, size).unwrap_or_else(|| data.to_vec());
+                    image::load_from_memory_with_format(&data, image::ImageFormat::Ico)
+                        .map_err(|e| e.into())
+                }
+
                 // Use image to decode other
                 Some(_) => image_reader.decode().map_err(|e| e.into()),
 
                 // We don't know the format
                 None => Err(FetchFaviconError::CannotDecode),
-            }
+            };
+            image_data.map(|image_data| (image_data, Vec::new()))
         })
         .await??;
 
         Ok(Self {
-            data: image_data,
             format: image_format,
+            frames,
+            ..Self::from_data(image_data)
         })
     }
+
+    /// Render an svg, loading the images it references as allowed by the fetch options
+    async fn render_svg(
+        client: &Client,
+        svg: String,
+        svg_url: &Url,
+        size: u32,
+        fetch_options: &FetchOptions,
+    ) -> Result<Self, FetchFaviconError> {
+        if !fetch_options.svg_remote_images {
+            return Ok(Self::from_svg_str(svg, size)?);
+        }
+
+        let images = fetch_svg_images(client, &svg, svg_url, fetch_options).await;
+        Ok(Self::from_svg_str_with_images(svg, size, images)?)
+    }
+}
+
+/// Give up on a fetch once the total timeout has passed
+async fn with_deadline<T>(
+    fetch_options: &FetchOptions,
+    fetch: impl std::future::Future<Output = Result<T, FetchFaviconError>>,
+) -> Result<T, FetchFaviconError> {
+    tokio::time::timeout(fetch_options.total_timeout, fetch)
+        .await
+        .map_err(|_| FetchFaviconError::TimedOut(fetch_options.total_timeout))?
+}
+
+/// Read a favicon from the disk cache, including expired favicons that could be revalidated
+async fn read_disk_cache(
+    cache: &DiskCache,
+    target_url: &Url,
+    options: &SelectionOptions,
+    size: u32,
+) -> Option<(CacheMetadata, Vec<u8>)> {
+    let (cache, target_url, options) = (cache.clone(), target_url.clone(), options.clone());
+    tokio::task::spawn_blocking(move || cache.get(&target_url, &options, size))
+        .await
+        .ok()?
+}
+
+/// Store a fetched favicon on disk. Favicons that can't be stored are only fetched again.
+async fn store_in_disk_cache(cache: &DiskCache, metadata: CacheMetadata, body: Vec<u8>) {
+    let cache = cache.clone();
+    let _ = tokio::task::spawn_blocking(move || cache.insert(&metadata, &body)).await;
+}
+
+/// The value of a header, if it is present and valid text
+fn header_string(headers: &HeaderMap, name: HeaderName) -> Option<String> {
+    headers
+        .get(name)
+        .and_then(|value| value.to_str().ok())
+        .map(|value| value.to_owned())
+}
+
+/// Send a GET request, retrying connection and server errors with exponential backoff
+async fn send_request<E>(
+    client: &Client,
+    url: &Url,
+    fetch_options: &FetchOptions,
+) -> Result<Response, E>
+where
+    E: From<reqwest::Error> + From<ForbiddenAddress> + From<HostNotAllowed>,
+{
+    let res =
+        send_conditional_request::<E>(client, url, fetch_options, &Validators::default()).await?;
+    Ok(res.expect("Requests without validators are always modified"))
+}
+
+/// Send a GET request that is conditional on the validators, returning `None` if the
+/// response is `304 Not Modified`
+async fn send_conditional_request<E>(
+    client: &Client,
+    url: &Url,
+    fetch_options: &FetchOptions,
+    validators: &Validators,
+) -> Result<Option<Response>, E>
+where
+    E: From<reqwest::Error> + From<ForbiddenAddress> + From<HostNotAllowed>,
+{
+    // Ip address hosts are connected to directly, without going through the guard's resolver
+    if let Some(guard) = &fetch_options.address_guard {
+        guard.check_url(url)?;
+    }
+    fetch_options.host_rules.check_url(url)?;
+
+    let mut attempt = 0;
+    loop {
+        let res = validators
+            .apply(client.get(url.clone()).header(USER_AGENT, BOT_USER_AGENT))
+            .send()
+            .await;
+
+        let retryable = match &res {
+            Ok(res) => res.status().is_server_error(),
+            Err(err) => err.is_connect() && ForbiddenAddress::find(err).is_none(),
+        };
+        if !retryable || attempt >= fetch_options.retries {
+            // Only trust that nothing changed if the request was conditional
+            return match res {
+                Ok(res) if res.status() == StatusCode::NOT_MODIFIED && !validators.is_empty() => {
+                    Ok(None)
+                }
+                res => res.map(Some).map_err(E::from),
+            };
+        }
+
+        tokio::time::sleep(RETRY_BACKOFF * 2u32.saturating_pow(attempt)).await;
+        attempt += 1;
+    }
+}
+
+/// Read a response body, failing if it is larger than `max_bytes`
+async fn read_body_limited<E>(mut res: Response, max_bytes: usize) -> Result<Vec<u8>, E>
+where
+    E: From<reqwest::Error> + From<BodyTooLarge>,
+{
+    if res
+        .content_length()
+        .is_some_and(|len| len > max_bytes as u64)
+    {
+        return Err(BodyTooLarge(max_bytes).into());
+    }
+
+    let mut body = Vec::new();
+    while let Some(chunk) = res.chunk().await? {
+        if body.len() + chunk.len() > max_bytes {
+            return Err(BodyTooLarge(max_bytes).into());
+        }
+        body.extend_from_slice(&chunk);
+    }
+
+    Ok(body)
+}
+
+/// Decode the content type and body of a base64 or percent encoded data url
+fn decode_data_url(url: &Url) -> Result<(Option<String>, Vec<u8>), FetchFaviconError> {
+    let data_url = DataUrl::process(url.as_str()).map_err(|_| FetchFaviconError::InvalidDataUrl)?;
+    let mime_type = data_url.mime_type();
+    let content_type = format!("{}/{}", mime_type.type_, mime_type.subtype);
+    let (body, _) = data_url
+        .decode_to_vec()
+        .map_err(|_| FetchFaviconError::InvalidDataUrl)?;
+
+    Ok((Some(content_type), body))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use base64::Engine;
+
+    const SVG: &str = r#"<svg xmlns="http://www.w3.org/2000/svg" viewBox="0 0 16 16"></svg>"#;
+
+    fn data_url(url: &str) -> Result<(Option<String>, Vec<u8>), FetchFaviconError> {
+        decode_data_url(&Url::parse(url).unwrap())
+    }
+
+    #[test]
+    fn decodes_base64_data_urls() {
+        let (content_type, body) = data_url("data:image/png;base64,iVBORw0KGgo=").unwrap();
+        assert_eq!(content_type.as_deref(), Some("image/png"));
+        assert_eq!(body, b"\x89PNG\r\n\x1a\n");
+
+        let base64 = base64::engine::general_purpose::STANDARD.encode(SVG);
+        let (content_type, body) =
+            data_url(&format!("data:image/svg+xml;base64,{base64}")).unwrap();
+        assert_eq!(content_type.as_deref(), Some("image/svg+xml"));
+        assert_eq!(body, SVG.as_bytes());
+    }
+
+    #[test]
+    fn decodes_percent_encoded_data_urls() {
+        let (content_type, body) = data_url(
+            "data:image/svg+xml;charset=utf-8,%3Csvg%20xmlns=%22http://www.w3.org/2000/svg%22%20viewBox=%220%200%2016%2016%22%3E%3C/svg%3E",
+        )
+        .unwrap();
+        assert_eq!(content_type.as_deref(), Some("image/svg+xml"));
+        assert_eq!(body, SVG.as_bytes());
+
+        // Unencoded markup is allowed too
+        let (_, body) = data_url(&format!("data:image/svg+xml,{SVG}")).unwrap();
+        assert_eq!(body, SVG.as_bytes());
+    }
+
+    #[test]
+    fn defaults_data_urls_to_plain_text() {
+        let (content_type, body) = data_url("data:,hello").unwrap();
+        assert_eq!(content_type.as_deref(), Some("text/plain"));
+        assert_eq!(body, b"hello");
+    }
+
+    #[test]
+    fn rejects_invalid_data_urls() {
+        assert!(matches!(
+            data_url("data:image/png;base64,not*base64"),
+            Err(FetchFaviconError::InvalidDataUrl)
+        ));
+        assert!(matches!(
+            data_url("data:image/png"),
+            Err(FetchFaviconError::InvalidDataUrl)
+        ));
+    }
 }
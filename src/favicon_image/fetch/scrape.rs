@@ -1,21 +1,72 @@
 //! Methods for scraping a website to determine the available favicon urls
 
-use reqwest::{header::USER_AGENT, Client};
+use std::collections::HashSet;
+
+use reqwest::{Client, StatusCode};
 use thiserror::Error;
 use url::Url;
 
-use super::BOT_USER_AGENT;
+use super::candidate::{
+    parse_sizes, rank_candidates, CandidateSource, FaviconCandidate, IconRel, IconSize,
+    SelectionOptions,
+};
+use super::manifest::fetch_manifest_icons;
+use super::{
+    find_source, read_body_limited, send_conditional_request, BodyTooLarge, FetchOptions,
+    ForbiddenAddress, HostNotAllowed, Validators,
+};
+
+const APPLE_TOUCH_ICON_SIZE: u32 = 180;
+const MAX_META_REFRESH_HOPS: usize = 5;
+/// Longest delay in seconds of a meta refresh followed from a page that has its own icons
+const MAX_META_REFRESH_DELAY: u64 = 5;
 
+/// The favicon candidates found for a website
 #[derive(Debug, Clone)]
-struct Link {
-    href: String,
-    size: usize,
+pub struct ScrapedCandidates {
+    /// Every page visited while following meta refresh redirects, ending with the scraped page
+    pub redirect_chain: Vec<Url>,
+    /// Candidates ranked from most to least suitable
+    pub candidates: Vec<FaviconCandidate>,
+    /// Validators of the page, empty if a meta refresh was followed
+    pub validators: Validators,
+    /// Status of the scraped page, which is searched for icons even if it is an error page
+    pub status: StatusCode,
+}
+
+/// The relevant parts of an html document
+struct ParsedPage {
+    candidates: Vec<FaviconCandidate>,
+    manifest_url: Option<Url>,
+    refresh: Option<MetaRefresh>,
+}
+
+/// A meta refresh redirect to another page
+struct MetaRefresh {
+    /// Seconds before the page is refreshed
+    delay: u64,
+    url: Url,
+}
+
+impl ParsedPage {
+    /// Whether a meta refresh should be followed, which is only if it is quick or the
+    /// page has no icons of its own
+    fn should_follow(&self, refresh: &MetaRefresh) -> bool {
+        refresh.delay <= MAX_META_REFRESH_DELAY
+            || (self.candidates.is_empty() && self.manifest_url.is_none())
+    }
 }
 
 #[derive(Error, Debug)]
 pub enum ScrapeError {
     #[error(transparent)]
-    Network(#[from] reqwest::Error),
+    Network(reqwest::Error),
+
+    #[error(transparent)]
+    ForbiddenAddress(#[from] ForbiddenAddress),
+
+    #[error(transparent)]
+    HostNotAllowed(#[from] HostNotAllowed),
 
     #[error(transparent)]
     HTMLParse(#[from] tl::ParseError),
@@ -23,73 +74,396 @@ pub enum ScrapeError {
     #[error(transparent)]
     URLParse(#[from] url::ParseError),
 
-    #[error("link not found")]
-    LinkNotFound,
+    #[error("Failed to parse manifest: {0}")]
+    ManifestParse(#[from] serde_json::Error),
+
+    #[error(transparent)]
+    BodyTooLarge(#[from] BodyTooLarge),
+
+    #[error("Page has not been modified")]
+    NotModified,
+}
+
+impl From<reqwest::Error> for ScrapeError {
+    fn from(err: reqwest::Error) -> Self {
+        if let Some(forbidden) = ForbiddenAddress::find(&err) {
+            return Self::ForbiddenAddress(forbidden);
+        }
+        match find_source::<HostNotAllowed>(&err) {
+            Some(not_allowed) => Self::HostNotAllowed(not_allowed),
+            None => Self::Network(err),
+        }
+    }
 }
 
-/// Scrape the <link /> tags from a given URL to find a favicon url
-pub async fn scrape_link_tags(
+/// Scrape the <link /> tags and manifest of a given URL to find every favicon candidate,
+/// ranked from most to least suitable for the preferred size. The page is requested
+/// conditionally on the validators, failing with `NotModified` if it is unchanged.
+pub async fn scrape_candidates(
     client: &Client,
     url: &Url,
     preferred_size: u32,
-) -> Result<Url, ScrapeError> {
-    let res = client
-        .get(url.clone())
-        .header(USER_AGENT, BOT_USER_AGENT)
-        .send()
-        .await?;
-    let html = res.text().await?;
-
-    let dom = tl::parse(&html, tl::ParserOptions::default())?;
+    options: &SelectionOptions,
+    fetch_options: &FetchOptions,
+    validators: &Validators,
+) -> Result<ScrapedCandidates, ScrapeError> {
+    let mut redirect_chain = Vec::new();
+    let mut request_url = url.clone();
+    let mut page_validators = Validators::default();
+    let (page, page_url, status) = loop {
+        // Only the requested page can be unchanged, not the pages it refreshes to
+        let validators = match redirect_chain.is_empty() {
+            true => validators,
+            false => &Validators::default(),
+        };
+        fetch_options.host_rules.check_page_url(&request_url)?;
+        let res = send_conditional_request::<ScrapeError>(
+            client,
+            &request_url,
+            fetch_options,
+            validators,
+        )
+        .await?
+        .ok_or(ScrapeError::NotModified)?;
+        if redirect_chain.is_empty() {
+            page_validators = Validators::from_headers(res.headers());
+        }
+
+        // Resolve against the page's final url after any redirects, which must be allowed too
+        let page_url = res.url().clone();
+        fetch_options.host_rules.check_page_url(&page_url)?;
+        let status = res.status();
+        let html = read_body_limited::<ScrapeError>(res, fetch_options.max_html_bytes).await?;
+        let html = String::from_utf8_lossy(&html);
+        let page = parse_page(&html, &page_url)?;
+        redirect_chain.push(page_url.clone());
+
+        // Follow meta refresh redirects to another page
+        match &page.refresh {
+            Some(refresh)
+                if refresh.url != page_url
+                    && redirect_chain.len() <= MAX_META_REFRESH_HOPS
+                    && page.should_follow(refresh) =>
+            {
+                request_url = refresh.url.clone();
+                page_validators = Validators::default();
+            }
+            _ => break (page, page_url, status),
+        }
+    };
+    let mut candidates = page.candidates;
+    let manifest_url = page.manifest_url;
+
+    // Include any icons listed in the web app manifest, ignoring manifests that fail to load
+    if let Some(manifest_url) = manifest_url {
+        if let Ok(icons) = fetch_manifest_icons(client, &manifest_url, fetch_options).await {
+            candidates.extend(
+                icons
+                    .into_iter()
+                    .filter(|icon| !icon.is_monochrome())
+                    .filter_map(|icon| {
+                        Some(FaviconCandidate {
+                            url: manifest_url.join(&icon.src).ok()?,
+                            sizes: icon.sizes.as_deref().map(parse_sizes).unwrap_or_default(),
+                            mime_type: icon.mime_type,
+                            source: CandidateSource::Manifest,
+                            rel: None,
+                            color: None,
+                            media: None,
+                        })
+                    }),
+            );
+        }
+    }
+
+    // Always fall back to the conventional favicon location
+    let mut candidates = rank_candidates(candidates, preferred_size, options);
+    let default = FaviconCandidate::default_for(&page_url);
+    if !candidates
+        .iter()
+        .any(|candidate| candidate.url == default.url)
+    {
+        candidates.push(default);
+    }
+
+    Ok(ScrapedCandidates {
+        redirect_chain,
+        candidates,
+        validators: page_validators,
+        status,
+    })
+}
+
+/// Parse the icon links, manifest url and meta refresh url out of an html document
+fn parse_page(html: &str, page_url: &Url) -> Result<ParsedPage, ScrapeError> {
+    let dom = tl::parse(html, tl::ParserOptions::default())?;
     let parser = dom.parser();
-    let mut links: Vec<_> = dom
+
+    // Relative urls are resolved against the first <base href> if present
+    let base_url = dom
+        .query_selector("base[href]")
+        .unwrap()
+        .filter_map(|base| {
+            base.get(parser)?
+                .as_tag()?
+                .attributes()
+                .get("href")
+                .flatten()
+                .and_then(|href| page_url.join(&href.as_utf8_str()).ok())
+        })
+        .next();
+    let url = base_url.as_ref().unwrap_or(page_url);
+    let links = dom
         .query_selector("link[rel*=\"icon\"]")
         .unwrap()
         .map(|link| link.get(parser).unwrap().as_tag().unwrap().attributes())
         .filter_map(|attr| match attr.get("href").flatten() {
             Some(href) => {
-                if let Some(media) = attr.get("media").flatten() {
-                    if String::from(media.as_utf8_str())
-                        .replace(' ', "")
-                        .to_ascii_lowercase()
-                        .contains("prefers-color-scheme:dark")
-                    {
-                        return None;
-                    }
-                }
-                Some(Link {
-                    href: href.as_utf8_str().into_owned(),
-                    size: attr
-                        .get("sizes")
+                let attr_string = |name: &str| {
+                    attr.get(name)
                         .flatten()
-                        .and_then(|sizes| {
-                            sizes
-                                .as_utf8_str()
-                                .split_once('x')
-                                .and_then(|(size, _)| size.parse().ok())
-                        })
-                        .unwrap_or(0),
+                        .map(|value| value.as_utf8_str().into_owned())
+                };
+                let rel = attr_string("rel");
+                let mut sizes = attr_string("sizes")
+                    .as_deref()
+                    .map(parse_sizes)
+                    .unwrap_or_default();
+
+                // Apple touch icons are usually unsized, but expected to be 180px
+                if sizes.is_empty()
+                    && rel.as_deref().map(IconRel::from_rel) == Some(IconRel::AppleTouchIcon)
+                {
+                    sizes.push(IconSize::Fixed {
+                        width: APPLE_TOUCH_ICON_SIZE,
+                        height: APPLE_TOUCH_ICON_SIZE,
+                    });
+                }
+
+                Some(FaviconCandidate {
+                    url: url.join(&href.as_utf8_str()).ok()?,
+                    sizes,
+                    mime_type: attr_string("type"),
+                    source: CandidateSource::Link,
+                    rel,
+                    color: attr_string("color"),
+                    media: attr_string("media"),
                 })
             }
             None => None,
         })
         .collect();
 
-    if links.is_empty() {
-        return Err(ScrapeError::LinkNotFound);
+    let manifest_url = dom
+        .query_selector("link[rel~=\"manifest\"]")
+        .unwrap()
+        .filter_map(|link| {
+            link.get(parser)?
+                .as_tag()?
+                .attributes()
+                .get("href")
+                .flatten()
+                .and_then(|href| url.join(&href.as_utf8_str()).ok())
+        })
+        .next();
+
+    // Refresh tags inside <noscript> are only for browsers without javascript, and usually
+    // lead to a fallback page rather than the site itself
+    let noscript_metas: HashSet<_> = dom
+        .query_selector("noscript")
+        .unwrap()
+        .filter_map(|noscript| {
+            noscript
+                .get(parser)?
+                .as_tag()?
+                .query_selector(parser, "meta")
+        })
+        .flatten()
+        .collect();
+
+    let refresh = dom
+        .query_selector("meta")
+        .unwrap()
+        .filter(|meta| !noscript_metas.contains(meta))
+        .filter_map(|meta| {
+            // Attribute names and values of meta refresh tags are often uppercase
+            let attr = meta.get(parser)?.as_tag()?.attributes();
+            let get_attr = |name: &str| {
+                attr.iter()
+                    .find(|(key, _)| key.eq_ignore_ascii_case(name))
+                    .and_then(|(_, value)| value)
+            };
+            if !get_attr("http-equiv")?
+                .trim()
+                .eq_ignore_ascii_case("refresh")
+            {
+                return None;
+            }
+            let content = get_attr("content")?;
+            let (delay, refresh_url) = parse_meta_refresh(&content)?;
+            Some(MetaRefresh {
+                delay,
+                url: url.join(refresh_url).ok()?,
+            })
+        })
+        .next();
+
+    Ok(ParsedPage {
+        candidates: links,
+        manifest_url,
+        refresh,
+    })
+}
+
+/// Parse the delay in whole seconds and the url out of a meta refresh `content` attribute,
+/// such as `0; url='/home'`
+fn parse_meta_refresh(content: &str) -> Option<(u64, &str)> {
+    let (delay, refresh) = content.split_once([';', ','])?;
+    let delay = delay.trim().split('.').next()?.parse().ok()?;
+    let refresh = refresh.trim_start();
+    let refresh = match refresh.get(..3) {
+        Some(prefix) if prefix.eq_ignore_ascii_case("url") => {
+            refresh[3..].trim_start().strip_prefix('=')?.trim_start()
+        }
+        _ => refresh,
+    };
+    let refresh = refresh.trim_end().trim_matches(|c| c == '\'' || c == '"');
+
+    (!refresh.is_empty()).then_some((delay, refresh))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn resolves_links_against_the_page_url() {
+        // The final url after any redirects, which is where relative links point
+        let page_url = Url::parse("https://www.example.com/blog/post").unwrap();
+        let page = parse_page(
+            r#"<link rel="icon" href="icon.png"><link rel="manifest" href="/site.webmanifest">"#,
+            &page_url,
+        )
+        .unwrap();
+        assert_eq!(
+            page.candidates[0].url.as_str(),
+            "https://www.example.com/blog/icon.png"
+        );
+        assert_eq!(
+            page.manifest_url.unwrap().as_str(),
+            "https://www.example.com/site.webmanifest"
+        );
     }
 
-    links.sort_unstable_by_key(|link| link.size);
+    #[test]
+    fn resolves_links_against_the_first_base() {
+        let page_url = Url::parse("https://example.com/blog/post").unwrap();
+        let page = parse_page(
+            r#"<head>
+                <base href="https://cdn.example.com/assets/">
+                <base href="/ignored/">
+                <link rel="shortcut icon" href="favicon.ico">
+                <link rel="apple-touch-icon" href="/touch.png">
+                <link rel="manifest" href="manifest.json">
+            </head>"#,
+            &page_url,
+        )
+        .unwrap();
+        let urls: Vec<&str> = page
+            .candidates
+            .iter()
+            .map(|candidate| candidate.url.as_str())
+            .collect();
+        assert_eq!(
+            urls,
+            [
+                "https://cdn.example.com/assets/favicon.ico",
+                "https://cdn.example.com/touch.png"
+            ]
+        );
+        assert_eq!(
+            page.manifest_url.unwrap().as_str(),
+            "https://cdn.example.com/assets/manifest.json"
+        );
 
-    // If an icon larger than the preferred size exists, use the closest
-    // to what we want instead of always using the largest image available
-    let filtered_links: Vec<_> = links
-        .iter()
-        .filter(|link| link.size < preferred_size as usize)
-        .collect();
-    if !filtered_links.is_empty() {
-        return Ok(url.join(&filtered_links.first().unwrap().href)?);
+        // Relative bases are resolved against the page url
+        let page = parse_page(
+            r#"<base href="../static/"><link rel="icon" href="icon.svg">"#,
+            &page_url,
+        )
+        .unwrap();
+        assert_eq!(
+            page.candidates[0].url.as_str(),
+            "https://example.com/static/icon.svg"
+        );
+    }
+
+    #[test]
+    fn parses_meta_refresh() {
+        assert_eq!(parse_meta_refresh("0; url=/home"), Some((0, "/home")));
+        assert_eq!(
+            parse_meta_refresh("5;URL = 'https://example.com/'"),
+            Some((5, "https://example.com/"))
+        );
+        assert_eq!(parse_meta_refresh("2.5, \"/next\""), Some((2, "/next")));
+        assert_eq!(parse_meta_refresh("3; /plain"), Some((3, "/plain")));
+        assert_eq!(parse_meta_refresh("30"), None);
+        assert_eq!(parse_meta_refresh("0; url="), None);
+        assert_eq!(parse_meta_refresh("soon; url=/home"), None);
     }
 
-    Ok(url.join(&links.last().unwrap().href)?)
+    #[test]
+    fn resolves_meta_refresh_against_base() {
+        let page_url = Url::parse("https://example.com/a/page").unwrap();
+        let page = parse_page(
+            r#"<head><base href="/b/"><meta http-equiv="Refresh" content="0; url=next"></head>"#,
+            &page_url,
+        )
+        .unwrap();
+        let refresh = page.refresh.as_ref().unwrap();
+        assert_eq!(refresh.url.as_str(), "https://example.com/b/next");
+        assert!(page.should_follow(refresh));
+    }
+
+    #[test]
+    fn ignores_meta_refresh_inside_noscript() {
+        let page_url = Url::parse("https://example.com/").unwrap();
+        let page = parse_page(
+            r#"<head>
+                <link rel="icon" href="/icon.svg">
+                <noscript><meta http-equiv="refresh" content="0; url=/nojs"></noscript>
+            </head>"#,
+            &page_url,
+        )
+        .unwrap();
+        assert!(page.refresh.is_none());
+        assert_eq!(page.candidates.len(), 1);
+
+        let page = parse_page(
+            r#"<noscript><meta http-equiv="refresh" content="0; url=/nojs"></noscript>
+            <meta http-equiv="refresh" content="0; url=/home">"#,
+            &page_url,
+        )
+        .unwrap();
+        assert_eq!(
+            page.refresh.unwrap().url.as_str(),
+            "https://example.com/home"
+        );
+    }
+
+    #[test]
+    fn follows_slow_meta_refresh_only_without_icons() {
+        let page_url = Url::parse("https://example.com/").unwrap();
+        let refresh = r#"<meta http-equiv="refresh" content="30; url=/next">"#;
+
+        let page = parse_page(refresh, &page_url).unwrap();
+        assert!(page.should_follow(page.refresh.as_ref().unwrap()));
+
+        let page = parse_page(
+            &format!(r#"{refresh}<link rel="icon" href="/icon.png">"#),
+            &page_url,
+        )
+        .unwrap();
+        assert!(!page.should_follow(page.refresh.as_ref().unwrap()));
+    }
 }
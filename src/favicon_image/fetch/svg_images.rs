@@ -0,0 +1,50 @@
+//! Methods for fetching the images referenced by an svg favicon
+
+use std::sync::Arc;
+
+use reqwest::Client;
+use url::Url;
+
+use super::{read_body_limited, send_request, FetchFaviconError, FetchOptions};
+use crate::favicon_image::svg::{image_hrefs, SvgImages};
+
+const MAX_SVG_IMAGES: usize = 8;
+
+/// Fetch the images an svg references from the same origin as the svg itself.
+/// Images that fail to load are skipped, and will not be rendered.
+pub async fn fetch_svg_images(
+    client: &Client,
+    svg: &str,
+    svg_url: &Url,
+    fetch_options: &FetchOptions,
+) -> SvgImages {
+    let mut images = SvgImages::new();
+    for href in image_hrefs(svg).into_iter().take(MAX_SVG_IMAGES) {
+        let Ok(image_url) = svg_url.join(&href) else {
+            continue;
+        };
+
+        // Only allow http images from the same origin
+        if !matches!(image_url.scheme(), "http" | "https") || image_url.origin() != svg_url.origin()
+        {
+            continue;
+        }
+
+        if let Ok(data) = fetch_image(client, image_url, fetch_options).await {
+            images.insert(href, Arc::new(data));
+        }
+    }
+
+    images
+}
+
+async fn fetch_image(
+    client: &Client,
+    image_url: Url,
+    fetch_options: &FetchOptions,
+) -> Result<Vec<u8>, FetchFaviconError> {
+    let res = send_request::<FetchFaviconError>(client, &image_url, fetch_options)
+        .await?
+        .error_for_status()?;
+    read_body_limited(res, fetch_options.max_image_bytes).await
+}
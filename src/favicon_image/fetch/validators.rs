@@ -0,0 +1,93 @@
+//! Validators for checking whether a previously fetched page or favicon has changed
+
+use reqwest::{
+    header::{HeaderMap, ETAG, IF_MODIFIED_SINCE, IF_NONE_MATCH, LAST_MODIFIED},
+    RequestBuilder,
+};
+use serde::{Deserialize, Serialize};
+
+use super::candidate::FaviconCandidate;
+use super::header_string;
+
+/// The `ETag` and `Last-Modified` headers of a response
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Validators {
+    pub etag: Option<String>,
+    pub last_modified: Option<String>,
+}
+
+impl Validators {
+    pub fn from_headers(headers: &HeaderMap) -> Self {
+        Self {
+            etag: header_string(headers, ETAG),
+            last_modified: header_string(headers, LAST_MODIFIED),
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.etag.is_none() && self.last_modified.is_none()
+    }
+
+    /// Make a request conditional, so it responds with `304 Not Modified` if nothing has changed
+    pub fn apply(&self, request: RequestBuilder) -> RequestBuilder {
+        let request = match &self.etag {
+            Some(etag) => request.header(IF_NONE_MATCH, etag),
+            None => request,
+        };
+        match &self.last_modified {
+            Some(last_modified) => request.header(IF_MODIFIED_SINCE, last_modified),
+            None => request,
+        }
+    }
+}
+
+/// Everything needed to check whether a previously fetched favicon has changed
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct FaviconValidators {
+    /// Validators of the page the favicon was found on, empty if it followed a meta refresh
+    pub page: Validators,
+    /// Candidate the favicon was fetched from, reused while the page is unchanged
+    pub candidate: FaviconCandidate,
+    /// Validators of the favicon image
+    pub icon: Validators,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use reqwest::header::HeaderValue;
+
+    #[test]
+    fn reads_and_sends_validators() {
+        let mut headers = HeaderMap::new();
+        headers.insert(ETAG, HeaderValue::from_static("\"abc\""));
+        headers.insert(
+            LAST_MODIFIED,
+            HeaderValue::from_static("Wed, 21 Oct 2015 07:28:00 GMT"),
+        );
+        let validators = Validators::from_headers(&headers);
+        assert!(!validators.is_empty());
+
+        let request = validators
+            .apply(reqwest::Client::new().get("https://example.com/favicon.ico"))
+            .build()
+            .unwrap();
+        assert_eq!(request.headers()[IF_NONE_MATCH], "\"abc\"");
+        assert_eq!(
+            request.headers()[IF_MODIFIED_SINCE],
+            "Wed, 21 Oct 2015 07:28:00 GMT"
+        );
+    }
+
+    #[test]
+    fn empty_validators_send_unconditional_requests() {
+        let validators = Validators::from_headers(&HeaderMap::new());
+        assert!(validators.is_empty());
+
+        let request = validators
+            .apply(reqwest::Client::new().get("https://example.com/favicon.ico"))
+            .build()
+            .unwrap();
+        assert!(request.headers().is_empty());
+    }
+}
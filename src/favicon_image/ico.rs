@@ -0,0 +1,162 @@
+//! Ico operations for favicon images
+
+use std::io;
+
+use image::{
+    codecs::ico::{IcoEncoder, IcoFrame},
+    imageops::FilterType,
+};
+
+use super::WriteImageError;
+
+const ICO_HEADER_LEN: usize = 6;
+const ICO_ENTRY_LEN: usize = 16;
+
+/// Largest dimension an ico entry can store
+pub const ICO_MAX_SIZE: u32 = 256;
+
+/// An image entry in the directory of an ico file
+struct IcoEntry<'a> {
+    size: u32,
+    bits_per_pixel: u16,
+    header: &'a [u8],
+    data: &'a [u8],
+}
+
+/// Parse the directory of an ico file, skipping any entries that are out of bounds
+fn parse_ico_entries(ico: &[u8]) -> Option<Vec<IcoEntry<'_>>> {
+    let header = ico.get(..ICO_HEADER_LEN)?;
+    if header[0..4] != [0, 0, 1, 0] {
+        return None;
+    }
+    let count = u16::from_le_bytes([header[4], header[5]]) as usize;
+
+    let entries = (0..count)
+        .filter_map(|i| {
+            let start = ICO_HEADER_LEN + i * ICO_ENTRY_LEN;
+            let header = ico.get(start..start + ICO_ENTRY_LEN)?;
+            let data_len = u32::from_le_bytes(header[8..12].try_into().unwrap()) as usize;
+            let data_offset = u32::from_le_bytes(header[12..16].try_into().unwrap()) as usize;
+            Some(IcoEntry {
+                // A dimension of 0 means 256px
+                size: match header[0] {
+                    0 => 256,
+                    width => width as u32,
+                },
+                bits_per_pixel: u16::from_le_bytes([header[6], header[7]]),
+                header,
+                data: ico.get(data_offset..data_offset.checked_add(data_len)?)?,
+            })
+        })
+        .collect();
+
+    Some(entries)
+}
+
+/// Extract the entry of an ico file that best matches the requested size, preferring
+/// the smallest entry at or above the size, as a new single image ico file
+pub fn select_ico_entry(ico: &[u8], size: u32) -> Option<Vec<u8>> {
+    let entries = parse_ico_entries(ico)?;
+    let entry = entries.iter().min_by_key(|entry| {
+        (
+            entry.size < size,
+            entry.size.abs_diff(size),
+            std::cmp::Reverse(entry.bits_per_pixel),
+        )
+    })?;
+
+    // Rebuild an ico containing only the selected entry
+    let mut selected = Vec::with_capacity(ICO_HEADER_LEN + ICO_ENTRY_LEN + entry.data.len());
+    selected.extend_from_slice(&[0, 0, 1, 0, 1, 0]);
+    selected.extend_from_slice(&entry.header[..12]);
+    selected.extend_from_slice(&((ICO_HEADER_LEN + ICO_ENTRY_LEN) as u32).to_le_bytes());
+    selected.extend_from_slice(entry.data);
+
+    Some(selected)
+}
+
+impl super::FaviconImage {
+    /// Write the image as an ico file with an entry resized to each of the given sizes
+    pub fn write_to_ico(
+        &self,
+        writer: &mut impl io::Write,
+        sizes: &[u32],
+    ) -> Result<(), WriteImageError> {
+        // Resize the source image once per entry
+        let pngs = sizes
+            .iter()
+            .map(|&size| {
+                let size = size.clamp(1, ICO_MAX_SIZE);
+                let data = self
+                    .data
+                    .resize_to_fill(size, size, FilterType::Lanczos3)
+                    .into_rgba8();
+                (size, data)
+            })
+            .collect::<Vec<_>>();
+
+        // Encode each entry as png
+        let frames = pngs
+            .iter()
+            .map(|(size, data)| IcoFrame::as_png(data, *size, *size, image::ColorType::Rgba8))
+            .collect::<Result<Vec<_>, _>>()?;
+
+        IcoEncoder::new(writer).encode_images(&frames)?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::favicon_image::FaviconImage;
+    use image::DynamicImage;
+
+    fn multi_size_ico(sizes: &[u32]) -> Vec<u8> {
+        let image = FaviconImage::from_data(DynamicImage::new_rgba8(8, 8));
+        let mut ico = Vec::new();
+        image.write_to_ico(&mut ico, sizes).unwrap();
+        ico
+    }
+
+    fn selected_size(ico: &[u8], size: u32) -> u32 {
+        let selected = select_ico_entry(ico, size).unwrap();
+        let image = image::load_from_memory_with_format(&selected, image::ImageFormat::Ico);
+        image.unwrap().width()
+    }
+
+    #[test]
+    fn parses_ico_entries() {
+        let ico = multi_size_ico(&[16, 32, 256, 512]);
+        let entries = parse_ico_entries(&ico).unwrap();
+        let sizes: Vec<u32> = entries.iter().map(|entry| entry.size).collect();
+        assert_eq!(sizes, [16, 32, 256, 256]);
+        assert!(entries.iter().all(|entry| entry.bits_per_pixel == 32));
+
+        assert!(parse_ico_entries(b"\x89PNG\r\n").is_none());
+        assert!(parse_ico_entries(&ico[..4]).is_none());
+    }
+
+    #[test]
+    fn skips_out_of_bounds_entries() {
+        let ico = multi_size_ico(&[16, 32]);
+        let entries = parse_ico_entries(&ico).unwrap();
+        let end = ico.len() - entries[1].data.len();
+
+        let entries = parse_ico_entries(&ico[..end]).unwrap();
+        assert_eq!(entries.len(), 1);
+        assert_eq!(entries[0].size, 16);
+    }
+
+    #[test]
+    fn selects_smallest_entry_at_or_above_size() {
+        let ico = multi_size_ico(&[16, 32, 48]);
+        assert_eq!(selected_size(&ico, 16), 16);
+        assert_eq!(selected_size(&ico, 20), 32);
+        assert_eq!(selected_size(&ico, 40), 48);
+
+        // Otherwise the largest entry
+        assert_eq!(selected_size(&ico, 256), 48);
+        assert!(select_ico_entry(&[0, 0, 1, 0, 0, 0], 16).is_none());
+    }
+}
@@ -1,19 +1,31 @@
 //! Wrapper for image data in various formats
 //! Implements file and network IO for favicon data
 
+mod animation;
 pub mod fetch;
+mod ico;
 mod svg;
 
+use animation::AnimationFrame;
+pub use ico::ICO_MAX_SIZE;
 use image::{imageops::FilterType, DynamicImage, ImageFormat};
 use std::io;
 use thiserror::Error;
 
 const WEBP_QUALITY: f32 = 70.0;
+#[cfg(feature = "avif")]
+const AVIF_QUALITY: u8 = 70;
+#[cfg(feature = "avif")]
+const AVIF_SPEED: u8 = 8;
 
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub struct FaviconImage {
     pub data: image::DynamicImage,
     pub format: Option<image::ImageFormat>,
+    /// Sizes to include when writing a multi-size ico, empty for a single image
+    pub ico_sizes: Vec<u32>,
+    /// Every frame of an animated image, empty for a still image
+    pub frames: Vec<AnimationFrame>,
 }
 
 #[derive(Error, Debug)]
@@ -29,16 +41,46 @@ pub enum WriteImageError {
 }
 
 impl FaviconImage {
+    /// A still image of an unknown format
+    pub fn from_data(data: DynamicImage) -> Self {
+        Self {
+            data,
+            format: None,
+            ico_sizes: Vec::new(),
+            frames: Vec::new(),
+        }
+    }
+
     pub fn write_to(
         &self,
         writer: &mut (impl io::Write + io::Seek),
         format: image::ImageFormat,
     ) -> Result<(), WriteImageError> {
+        // Seperately handle output of animations, in formats that support them
+        if self.is_animated() {
+            match format {
+                image::ImageFormat::Gif => return self.write_to_animated_gif(writer),
+                image::ImageFormat::WebP => return self.write_to_animated_webp(writer),
+                _ => {}
+            }
+        }
+
         // Seperately handle output of webp
         if format == image::ImageFormat::WebP {
             return self.write_to_webp(writer);
         }
 
+        // Seperately handle output of avif
+        #[cfg(feature = "avif")]
+        if format == image::ImageFormat::Avif {
+            return self.write_to_avif(writer);
+        }
+
+        // Seperately handle output of multi-size icos
+        if format == image::ImageFormat::Ico && !self.ico_sizes.is_empty() {
+            return self.write_to_ico(writer, &self.ico_sizes);
+        }
+
         // Convert image format to output format type
         let output_format: image::ImageOutputFormat = format.into();
 
@@ -70,9 +112,34 @@ impl FaviconImage {
         Ok(())
     }
 
+    #[cfg(feature = "avif")]
+    fn write_to_avif(&self, writer: &mut impl io::Write) -> Result<(), WriteImageError> {
+        use image::{codecs::avif::AvifEncoder, ImageEncoder};
+
+        // Use a faster speed than the default, as favicons are encoded per request
+        let data = self.data.to_rgba8();
+        AvifEncoder::new_with_speed_quality(writer, AVIF_SPEED, AVIF_QUALITY).write_image(
+            data.as_raw(),
+            data.width(),
+            data.height(),
+            image::ColorType::Rgba8,
+        )?;
+
+        Ok(())
+    }
+
     pub fn resize(self, size: u32) -> Self {
         let data = self.data.resize_to_fill(size, size, FilterType::Lanczos3);
-        Self { data, ..self }
+        let frames = self
+            .frames
+            .into_iter()
+            .map(|frame| frame.resize(size))
+            .collect();
+        Self {
+            data,
+            frames,
+            ..self
+        }
     }
 
     pub fn reformat(self, format: ImageFormat) -> Self {
@@ -81,6 +148,28 @@ impl FaviconImage {
             ..self
         }
     }
+
+    /// Replace the colour of every pixel while keeping its alpha, for monochrome icons
+    pub fn tint(self, color: image::Rgb<u8>) -> Self {
+        let mut data = self.data.into_rgba8();
+        for pixel in data.pixels_mut() {
+            pixel.0[..3].copy_from_slice(&color.0);
+        }
+        let frames = self
+            .frames
+            .into_iter()
+            .map(|frame| frame.tint(color))
+            .collect();
+        Self {
+            data: DynamicImage::ImageRgba8(data),
+            frames,
+            ..self
+        }
+    }
+
+    pub fn with_ico_sizes(self, ico_sizes: Vec<u32>) -> Self {
+        Self { ico_sizes, ..self }
+    }
 }
 
 #[cfg(feature = "server")]
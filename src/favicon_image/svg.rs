@@ -1,12 +1,43 @@
 //! Svg operations for favicon images
 
+use std::borrow::Cow;
+use std::collections::HashMap;
+use std::io::{self, Read};
+use std::sync::{Arc, Mutex};
+
+use flate2::read::GzDecoder;
 use image::{DynamicImage, RgbaImage};
 use lazy_static::lazy_static;
 use resvg::{
     tiny_skia,
-    usvg::{self, fontdb, Options, Size, TreeParsing, TreeTextToPath},
+    usvg::{
+        self, fontdb, ImageHrefResolver, ImageKind, Options, Size, TreeParsing, TreeTextToPath,
+    },
     Tree,
 };
+use thiserror::Error;
+use url::Url;
+
+const GZIP_MAGIC: [u8; 2] = [0x1f, 0x8b];
+const SNIFF_LEN: usize = 1024;
+
+/// Images referenced by an svg that were fetched ahead of rendering, keyed by href
+pub type SvgImages = HashMap<String, Arc<Vec<u8>>>;
+
+#[derive(Error, Debug)]
+pub enum SvgError {
+    #[error("Failed to parse svg: {0}")]
+    Parse(#[from] usvg::Error),
+
+    #[error("Cannot render svg at size {0}")]
+    InvalidSize(u32),
+
+    #[error("Failed to decompress svg: {0}")]
+    Decompress(#[from] io::Error),
+
+    #[error("Decompressed svg is larger than {0} bytes")]
+    TooLarge(usize),
+}
 
 // Load fonts once
 lazy_static! {
@@ -27,26 +58,309 @@ lazy_static! {
 
 impl super::FaviconImage {
     /// Rasterise an svg string to a formatless favicon image
-    pub fn from_svg_str(svg: String, size: u32) -> Self {
+    pub fn from_svg_str(svg: String, size: u32) -> Result<Self, SvgError> {
+        Self::from_svg_str_with_images(svg, size, SvgImages::new())
+    }
+
+    /// Rasterise an svg string to a formatless favicon image, using images fetched ahead of time
+    pub fn from_svg_str_with_images(
+        svg: String,
+        size: u32,
+        images: SvgImages,
+    ) -> Result<Self, SvgError> {
         let rtree = {
-            let mut tree = usvg::Tree::from_data(svg.as_bytes(), &Options::default()).unwrap();
+            let mut tree = usvg::Tree::from_data(svg.as_bytes(), &svg_options(images))?;
             tree.convert_text(&FONT_DB);
-            tree.size = tree
-                .size
-                .scale_to(Size::from_wh(size as f32, size as f32).unwrap());
+            tree.size = tree.size.scale_to(
+                Size::from_wh(size as f32, size as f32).ok_or(SvgError::InvalidSize(size))?,
+            );
             Tree::from_usvg(&tree)
         };
 
         let pixmap_size = rtree.size.to_int_size();
-        let mut pixmap = tiny_skia::Pixmap::new(pixmap_size.width(), pixmap_size.height()).unwrap();
+        let mut pixmap = tiny_skia::Pixmap::new(pixmap_size.width(), pixmap_size.height())
+            .ok_or(SvgError::InvalidSize(size))?;
         rtree.render(tiny_skia::Transform::default(), &mut pixmap.as_mut());
 
-        Self {
-            data: DynamicImage::ImageRgba8(
-                RgbaImage::from_raw(pixmap.width(), pixmap.height(), pixmap.data().to_vec())
-                    .unwrap(),
-            ),
-            format: None,
+        Ok(Self::from_data(DynamicImage::ImageRgba8(
+            RgbaImage::from_raw(pixmap.width(), pixmap.height(), pixmap.data().to_vec())
+                .expect("Pixmap data should match its size"),
+        )))
+    }
+}
+
+/// Options for parsing untrusted svgs. Images are only loaded from data urls or from
+/// those fetched ahead of time, and never from local files.
+fn svg_options(images: SvgImages) -> Options {
+    Options {
+        image_href_resolver: ImageHrefResolver {
+            resolve_data: ImageHrefResolver::default_data_resolver(),
+            resolve_string: Box::new(move |href, _| {
+                let data = images.get(href)?.clone();
+                match image::guess_format(&data).ok()? {
+                    image::ImageFormat::Png => Some(ImageKind::PNG(data)),
+                    image::ImageFormat::Jpeg => Some(ImageKind::JPEG(data)),
+                    image::ImageFormat::Gif => Some(ImageKind::GIF(data)),
+                    _ => None,
+                }
+            }),
+        },
+        ..Options::default()
+    }
+}
+
+/// Find the hrefs of every non-data image referenced by an svg
+pub fn image_hrefs(svg: &str) -> Vec<String> {
+    let hrefs = Arc::new(Mutex::new(Vec::new()));
+    let options = Options {
+        image_href_resolver: ImageHrefResolver {
+            resolve_data: ImageHrefResolver::default_data_resolver(),
+            resolve_string: Box::new({
+                let hrefs = hrefs.clone();
+                move |href, _| {
+                    hrefs.lock().unwrap().push(href.to_owned());
+                    None
+                }
+            }),
+        },
+        ..Options::default()
+    };
+    let _ = usvg::Tree::from_data(svg.as_bytes(), &options);
+
+    let hrefs = hrefs.lock().unwrap().clone();
+    hrefs
+}
+
+/// Whether a mime type, ignoring any parameters, is svg
+pub fn is_svg_mime_type(mime_type: &str) -> bool {
+    mime_type
+        .split(';')
+        .next()
+        .unwrap_or_default()
+        .trim()
+        .eq_ignore_ascii_case("image/svg+xml")
+}
+
+/// Whether the path of a url has an svg or svgz file extension
+pub fn has_svg_extension(url: &Url) -> bool {
+    let path = url.path().to_ascii_lowercase();
+    path.ends_with(".svg") || path.ends_with(".svgz")
+}
+
+/// Read svg markup from data, decompressing it if gzipped.
+/// Decompressed data larger than `max_bytes` is an error.
+pub fn svg_text(data: &[u8], max_bytes: usize) -> Result<String, SvgError> {
+    let data = if data.starts_with(&GZIP_MAGIC) {
+        let mut decompressed = Vec::new();
+        GzDecoder::new(data)
+            .take(max_bytes as u64 + 1)
+            .read_to_end(&mut decompressed)?;
+        if decompressed.len() > max_bytes {
+            return Err(SvgError::TooLarge(max_bytes));
+        }
+        Cow::Owned(decompressed)
+    } else {
+        Cow::Borrowed(data)
+    };
+    Ok(String::from_utf8_lossy(&data).into_owned())
+}
+
+/// Read svg markup from data that has no declared svg type, if it looks like an svg
+pub fn sniff_svg(data: &[u8], max_bytes: usize) -> Result<Option<String>, SvgError> {
+    // Avoid decoding the whole body of other image formats
+    if !data.starts_with(&GZIP_MAGIC) {
+        let prefix = String::from_utf8_lossy(&data[..data.len().min(SNIFF_LEN)]);
+        if !is_svg_text(&prefix) {
+            return Ok(None);
+        }
+    }
+    let svg = svg_text(data, max_bytes)?;
+    Ok(Some(svg).filter(|svg| is_svg_text(svg)))
+}
+
+/// Whether some text is an svg document, allowing for a leading xml
+/// declaration, doctype and comments before the `<svg>` root element
+fn is_svg_text(text: &str) -> bool {
+    let mut rest = text.trim_start_matches('\u{feff}').trim_start();
+    loop {
+        if rest.starts_with("<svg") {
+            return true;
         }
+        let end = if rest.starts_with("<?") {
+            "?>"
+        } else if rest.starts_with("<!--") {
+            "-->"
+        } else if rest.starts_with("<!") {
+            ">"
+        } else {
+            return false;
+        };
+        match rest.find(end) {
+            Some(i) => rest = rest[i + end.len()..].trim_start(),
+            None => return false,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::favicon_image::FaviconImage;
+    use base64::Engine;
+
+    const VALID_SVG: &str = r#"<svg xmlns="http://www.w3.org/2000/svg" viewBox="0 0 16 16"><rect width="16" height="16" fill="red"/></svg>"#;
+    const MALFORMED_SVG: &str =
+        r#"<svg xmlns="http://www.w3.org/2000/svg" viewBox="0 0 16 16"><rect"#;
+    const NOT_SVG: &str = "<html><body>Not found</body></html>";
+    const ZERO_SIZED_SVG: &str =
+        r#"<svg xmlns="http://www.w3.org/2000/svg" width="0" height="0"></svg>"#;
+    const EMPTY_SVG: &str = "";
+
+    #[test]
+    fn renders_valid_svg() {
+        let image = FaviconImage::from_svg_str(VALID_SVG.to_owned(), 32).unwrap();
+        assert_eq!((image.data.width(), image.data.height()), (32, 32));
+    }
+
+    #[test]
+    fn broken_svgs_are_errors() {
+        for svg in [MALFORMED_SVG, NOT_SVG, ZERO_SIZED_SVG, EMPTY_SVG] {
+            assert!(matches!(
+                FaviconImage::from_svg_str(svg.to_owned(), 32),
+                Err(SvgError::Parse(_))
+            ));
+        }
+    }
+
+    fn gzip(data: &[u8]) -> Vec<u8> {
+        use std::io::Write;
+        let mut encoder = flate2::write::GzEncoder::new(Vec::new(), flate2::Compression::fast());
+        encoder.write_all(data).unwrap();
+        encoder.finish().unwrap()
+    }
+
+    #[test]
+    fn detects_svg_mime_types_and_extensions() {
+        assert!(is_svg_mime_type("image/svg+xml"));
+        assert!(is_svg_mime_type("Image/SVG+XML; charset=utf-8"));
+        assert!(!is_svg_mime_type("text/xml"));
+        assert!(!is_svg_mime_type("image/png"));
+
+        let url = |path| {
+            Url::parse("https://example.com")
+                .unwrap()
+                .join(path)
+                .unwrap()
+        };
+        assert!(has_svg_extension(&url("/icon.svg")));
+        assert!(has_svg_extension(&url("/ICON.SVGZ")));
+        assert!(!has_svg_extension(&url("/icon.png")));
+    }
+
+    #[test]
+    fn detects_svg_text() {
+        assert!(is_svg_text(VALID_SVG));
+        assert!(is_svg_text(&format!(
+            "\u{feff}<?xml version=\"1.0\"?>\n<!DOCTYPE svg PUBLIC \"-//W3C//DTD SVG 1.1//EN\" \"http://www.w3.org/Graphics/SVG/1.1/DTD/svg11.dtd\">\n<!-- <html> -->\n{VALID_SVG}"
+        )));
+        assert!(!is_svg_text(NOT_SVG));
+        assert!(!is_svg_text("<?xml version=\"1.0\"?><rss></rss>"));
+        assert!(!is_svg_text("<!-- unclosed comment <svg>"));
+        assert!(!is_svg_text(EMPTY_SVG));
+    }
+
+    #[test]
+    fn sniffs_svgs() {
+        assert_eq!(
+            sniff_svg(VALID_SVG.as_bytes(), 1024).unwrap().as_deref(),
+            Some(VALID_SVG)
+        );
+        assert_eq!(
+            sniff_svg(&gzip(VALID_SVG.as_bytes()), 1024)
+                .unwrap()
+                .as_deref(),
+            Some(VALID_SVG)
+        );
+        assert_eq!(sniff_svg(NOT_SVG.as_bytes(), 1024).unwrap(), None);
+        assert_eq!(sniff_svg(&gzip(NOT_SVG.as_bytes()), 1024).unwrap(), None);
+        assert_eq!(sniff_svg(&[0x89, b'P', b'N', b'G'], 1024).unwrap(), None);
+    }
+
+    #[test]
+    fn limits_decompressed_svgs() {
+        let svgz = gzip(VALID_SVG.as_bytes());
+        assert_eq!(svg_text(&svgz, VALID_SVG.len()).unwrap(), VALID_SVG);
+        assert!(matches!(
+            svg_text(&svgz, VALID_SVG.len() - 1),
+            Err(SvgError::TooLarge(_))
+        ));
+        assert!(matches!(
+            sniff_svg(&gzip(&vec![b' '; 1 << 20]), 1024),
+            Err(SvgError::TooLarge(1024))
+        ));
+        assert!(matches!(
+            svg_text(&GZIP_MAGIC, 1024),
+            Err(SvgError::Decompress(_))
+        ));
+    }
+
+    #[test]
+    fn zero_size_is_an_error() {
+        assert!(matches!(
+            FaviconImage::from_svg_str(VALID_SVG.to_owned(), 0),
+            Err(SvgError::InvalidSize(0))
+        ));
+    }
+
+    fn red_png_data_url() -> String {
+        let image =
+            DynamicImage::ImageRgba8(RgbaImage::from_pixel(4, 4, image::Rgba([255, 0, 0, 255])));
+        let mut png = std::io::Cursor::new(Vec::new());
+        image
+            .write_to(&mut png, image::ImageOutputFormat::Png)
+            .unwrap();
+        let base64 = base64::engine::general_purpose::STANDARD.encode(png.into_inner());
+        format!("data:image/png;base64,{base64}")
+    }
+
+    #[test]
+    fn renders_data_url_images() {
+        let svg = format!(
+            r#"<svg xmlns="http://www.w3.org/2000/svg" viewBox="0 0 4 4"><image width="4" height="4" href="{}"/></svg>"#,
+            red_png_data_url()
+        );
+        let image = FaviconImage::from_svg_str(svg, 4).unwrap();
+        assert_eq!(image.data.to_rgba8().get_pixel(2, 2).0, [255, 0, 0, 255]);
+    }
+
+    #[test]
+    fn finds_non_data_image_hrefs() {
+        let svg = format!(
+            r#"<svg xmlns="http://www.w3.org/2000/svg" viewBox="0 0 4 4"><image width="4" height="4" href="{}"/><image width="4" height="4" href="/etc/hosts"/></svg>"#,
+            red_png_data_url()
+        );
+        assert_eq!(image_hrefs(&svg), ["/etc/hosts"]);
+    }
+
+    #[test]
+    fn local_files_are_not_loaded() {
+        let path =
+            std::env::temp_dir().join(format!("favicon-rover-svg-test-{}.png", std::process::id()));
+        std::fs::write(
+            &path,
+            data_url::DataUrl::process(&red_png_data_url())
+                .unwrap()
+                .decode_to_vec()
+                .unwrap()
+                .0,
+        )
+        .unwrap();
+        let svg = format!(
+            r#"<svg xmlns="http://www.w3.org/2000/svg" viewBox="0 0 4 4"><image width="4" height="4" href="{}"/></svg>"#,
+            path.display()
+        );
+        let image = FaviconImage::from_svg_str(svg, 4);
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(image.unwrap().data.to_rgba8().get_pixel(2, 2).0[3], 0);
     }
 }
@@ -1,4 +1,5 @@
 mod cli_args;
+mod disk_cache;
 mod favicon_image;
 mod image_writer;
 
@@ -8,11 +9,14 @@ mod server;
 use std::io::Write;
 
 use clap::Parser;
-use cli_args::{Cli, Command};
-use favicon_image::FaviconImage;
+use cli_args::{CacheCommand, Cli, Command};
+use disk_cache::{DiskCache, Removed};
+use favicon_image::{
+    fetch::{scrape_candidates, FetchOptions, SelectionOptions, Validators},
+    FaviconImage, ICO_MAX_SIZE,
+};
 use image::ImageFormat;
 use image_writer::ImageWriter;
-use reqwest::Client;
 
 pub const DEFAULT_IMAGE_SIZE: u32 = 256;
 pub const DEFAULT_IMAGE_FORMAT: ImageFormat = ImageFormat::Jpeg;
@@ -25,12 +29,30 @@ async fn main() {
             url,
             out,
             size,
+            selection,
+            fetch,
             format,
+            ico_sizes,
         }) => {
             // Get favicon (will not gen a fallback)
-            let fetch_size = size.unwrap_or(DEFAULT_IMAGE_SIZE);
-            let client = Client::new();
-            let mut favicon = match FaviconImage::fetch_for_url(&client, &url, fetch_size).await {
+            let fetch_size = ico_sizes
+                .iter()
+                .map(|&ico_size| ico_size.min(ICO_MAX_SIZE))
+                .chain(size)
+                .max()
+                .unwrap_or(DEFAULT_IMAGE_SIZE);
+            let selection = SelectionOptions::from(selection);
+            let fetch_options = FetchOptions::from(fetch);
+            let client = fetch_options.build_client();
+            let mut favicon = match FaviconImage::fetch_for_url(
+                &client,
+                &url,
+                fetch_size,
+                &selection,
+                &fetch_options,
+            )
+            .await
+            {
                 Ok(favicon) => favicon,
                 Err(err) => {
                     eprintln!("failed to fetch favicon: {}", err);
@@ -44,8 +66,9 @@ async fn main() {
                     .and_then(|path| image::ImageFormat::from_path(path).ok())
             });
 
-            // Resize the image
-            if let Some(size) = size {
+            // Resize the image, unless each size of a multi-size ico is resized when written
+            let writes_ico = format == Some(ImageFormat::Ico) && !ico_sizes.is_empty();
+            if let Some(size) = size.filter(|_| !writes_ico) {
                 favicon = favicon.resize(size);
             }
 
@@ -53,6 +76,7 @@ async fn main() {
             if let Some(format) = format {
                 favicon = favicon.reformat(format);
             }
+            favicon = favicon.with_ico_sizes(ico_sizes);
 
             // Write the image
             let mut writer = ImageWriter::new(out);
@@ -60,6 +84,65 @@ async fn main() {
             writer.flush().unwrap();
         }
 
+        Some(Command::List {
+            url,
+            size,
+            selection,
+            fetch,
+        }) => {
+            let selection = SelectionOptions::from(selection);
+            let fetch_options = FetchOptions::from(fetch);
+            let client = fetch_options.build_client();
+            let scraped = match scrape_candidates(
+                &client,
+                &url,
+                size.unwrap_or(DEFAULT_IMAGE_SIZE),
+                &selection,
+                &fetch_options,
+                &Validators::default(),
+            )
+            .await
+            {
+                Ok(scraped) => scraped,
+                Err(err) => {
+                    eprintln!("failed to scrape favicon candidates: {}", err);
+                    return;
+                }
+            };
+
+            for page_url in scraped.redirect_chain.iter().skip(1) {
+                eprintln!("followed meta refresh to {}", page_url);
+            }
+            for candidate in scraped.candidates {
+                println!("{}", candidate);
+            }
+        }
+
+        Some(Command::Cache {
+            disk_cache,
+            command,
+        }) => {
+            let cache = DiskCache::from(disk_cache);
+            let result = match command {
+                CacheCommand::List => cache.entries().map(|entries| {
+                    for entry in entries {
+                        println!("{}", entry);
+                    }
+                }),
+                CacheCommand::Inspect { id } => cache.entry(&id).map(|entry| match entry {
+                    Some(entry) => {
+                        println!("{}", serde_json::to_string_pretty(&entry.metadata).unwrap())
+                    }
+                    None => eprintln!("no cached favicon with id {}", id),
+                }),
+                CacheCommand::Prune => cache.prune().map(print_removed),
+                CacheCommand::Clear => cache.clear().map(print_removed),
+            };
+            if let Err(err) = result {
+                eprintln!("failed to read cache at {}: {}", cache.dir.display(), err);
+            }
+        }
+
         #[cfg(feature = "server")]
         Some(Command::Serve(options)) => {
             server::start_server(options).await.unwrap();
@@ -68,3 +151,10 @@ async fn main() {
         None => {}
     }
 }
+
+fn print_removed(removed: Removed) {
+    println!(
+        "removed {} cached favicons ({} bytes)",
+        removed.entries, removed.bytes
+    );
+}
@@ -0,0 +1,344 @@
+//! In-memory cache of the favicons fetched by the server
+
+use std::num::NonZeroUsize;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use lru::LruCache;
+use url::Url;
+
+use crate::favicon_image::fetch::{FaviconValidators, SelectionOptions};
+use crate::favicon_image::FaviconImage;
+
+/// A target url without its fragment, and the options and size used to select its favicon.
+/// Size policies can choose different icons for different sizes, so each size is kept apart.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct CacheKey {
+    url: String,
+    selection: SelectionOptions,
+    size: u32,
+}
+
+impl CacheKey {
+    pub fn new(url: &Url, selection: &SelectionOptions, size: u32) -> Self {
+        // Fragments are never sent to the server, so don't change the favicon
+        let mut url = url.clone();
+        url.set_fragment(None);
+        Self {
+            url: url.into(),
+            selection: selection.clone(),
+            size,
+        }
+    }
+}
+
+/// How recently a cached favicon was fetched
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Freshness {
+    /// Within its ttl, so it can be served
+    Fresh,
+    /// Past its ttl but within the stale window, so it can be served while it is revalidated
+    Stale,
+    /// Past the stale window, so it must be revalidated before it is served
+    Expired,
+}
+
+/// Whether a response was served from the cache, sent as the `x-cache` header
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CacheStatus {
+    Hit,
+    Stale,
+    Miss,
+}
+
+impl CacheStatus {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Self::Hit => "hit",
+            Self::Stale => "stale",
+            Self::Miss => "miss",
+        }
+    }
+}
+
+/// A favicon kept in the cache
+#[derive(Debug, Clone)]
+pub struct CachedFavicon {
+    /// Favicon as fetched, before it is resized or reformatted for a response
+    pub image: FaviconImage,
+    /// Validators to check whether the favicon has changed once it expires
+    pub validators: FaviconValidators,
+    pub freshness: Freshness,
+}
+
+#[derive(Debug)]
+struct CacheEntry {
+    image: FaviconImage,
+    validators: FaviconValidators,
+    /// Approximate memory used by the favicon
+    bytes: usize,
+    fetched_at: Instant,
+    /// Whether the favicon is being revalidated in the background
+    refreshing: bool,
+}
+
+#[derive(Debug)]
+struct CacheEntries {
+    lru: LruCache<CacheKey, CacheEntry>,
+    /// Approximate memory used by every cached favicon
+    bytes: usize,
+}
+
+/// Least recently used favicons, keyed by target url, selection options and size
+#[derive(Debug)]
+pub struct FaviconCache {
+    entries: Mutex<CacheEntries>,
+    max_bytes: usize,
+    ttl: Duration,
+    stale_window: Duration,
+}
+
+impl FaviconCache {
+    pub fn new(
+        capacity: NonZeroUsize,
+        max_bytes: usize,
+        ttl: Duration,
+        stale_window: Duration,
+    ) -> Self {
+        Self {
+            entries: Mutex::new(CacheEntries {
+                lru: LruCache::new(capacity),
+                bytes: 0,
+            }),
+            max_bytes,
+            ttl,
+            stale_window,
+        }
+    }
+
+    /// Get a favicon selected for `size`. Expired favicons are kept until they are evicted,
+    /// so they can be revalidated.
+    pub fn get(&self, url: &Url, selection: &SelectionOptions, size: u32) -> Option<CachedFavicon> {
+        let mut entries = self.entries.lock().unwrap();
+        let entry = entries.lru.get(&CacheKey::new(url, selection, size))?;
+        let age = entry.fetched_at.elapsed();
+        let freshness = if age <= self.ttl {
+            Freshness::Fresh
+        } else if age <= self.ttl + self.stale_window {
+            Freshness::Stale
+        } else {
+            Freshness::Expired
+        };
+        Some(CachedFavicon {
+            image: entry.image.clone(),
+            validators: entry.validators.clone(),
+            freshness,
+        })
+    }
+
+    /// Mark a favicon as being revalidated in the background, returning false if it
+    /// already is or is no longer cached
+    pub fn begin_refresh(&self, url: &Url, selection: &SelectionOptions, size: u32) -> bool {
+        let mut entries = self.entries.lock().unwrap();
+        match entries.lru.peek_mut(&CacheKey::new(url, selection, size)) {
+            Some(entry) if !entry.refreshing => {
+                entry.refreshing = true;
+                true
+            }
+            _ => false,
+        }
+    }
+
+    /// Allow a favicon to be revalidated again after a background revalidation failed
+    pub fn end_refresh(&self, url: &Url, selection: &SelectionOptions, size: u32) {
+        let mut entries = self.entries.lock().unwrap();
+        if let Some(entry) = entries.lru.peek_mut(&CacheKey::new(url, selection, size)) {
+            entry.refreshing = false;
+        }
+    }
+
+    /// Cache a favicon, evicting the least recently used favicons until the cache fits
+    /// in `max_bytes`. Favicons larger than `max_bytes` aren't cached.
+    pub fn insert(
+        &self,
+        url: &Url,
+        selection: &SelectionOptions,
+        size: u32,
+        image: FaviconImage,
+        validators: FaviconValidators,
+    ) {
+        let key = CacheKey::new(url, selection, size);
+        let mut entries = self.entries.lock().unwrap();
+        let bytes = image_bytes(&image);
+        if bytes > self.max_bytes {
+            if let Some(old) = entries.lru.pop(&key) {
+                entries.bytes -= old.bytes;
+            }
+            return;
+        }
+
+        let entry = CacheEntry {
+            image,
+            validators,
+            bytes,
+            fetched_at: Instant::now(),
+            refreshing: false,
+        };
+        entries.bytes += bytes;
+        if let Some((_, old)) = entries.lru.push(key, entry) {
+            entries.bytes -= old.bytes;
+        }
+        while entries.bytes > self.max_bytes {
+            let Some((_, old)) = entries.lru.pop_lru() else {
+                break;
+            };
+            entries.bytes -= old.bytes;
+        }
+    }
+}
+
+/// Approximate memory used by the pixels of a favicon and its frames
+fn image_bytes(image: &FaviconImage) -> usize {
+    image.data.as_bytes().len()
+        + image
+            .frames
+            .iter()
+            .map(|frame| frame.data.as_raw().len())
+            .sum::<usize>()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::favicon_image::fetch::{FaviconCandidate, Validators};
+    use image::DynamicImage;
+
+    fn image() -> FaviconImage {
+        FaviconImage::from_data(DynamicImage::new_rgba8(4, 4))
+    }
+
+    fn validators(url: &Url) -> FaviconValidators {
+        FaviconValidators {
+            page: Validators::default(),
+            candidate: FaviconCandidate::default_for(url),
+            icon: Validators::default(),
+        }
+    }
+
+    fn insert(cache: &FaviconCache, url: &Url, selection: &SelectionOptions) {
+        cache.insert(url, selection, 256, image(), validators(url));
+    }
+
+    fn cache(capacity: usize, ttl: Duration) -> FaviconCache {
+        FaviconCache::new(
+            NonZeroUsize::new(capacity).unwrap(),
+            usize::MAX,
+            ttl,
+            Duration::ZERO,
+        )
+    }
+
+    #[test]
+    fn misses_for_other_sizes() {
+        let cache = cache(2, Duration::from_secs(60));
+        let url = Url::parse("https://example.com/#top").unwrap();
+        let selection = SelectionOptions::default();
+        insert(&cache, &url, &selection);
+
+        let normalized = Url::parse("https://EXAMPLE.com").unwrap();
+        assert!(cache.get(&normalized, &selection, 256).is_some());
+        assert!(cache.get(&normalized, &selection, 64).is_none());
+        assert!(cache.get(&normalized, &selection, 512).is_none());
+    }
+
+    #[test]
+    fn misses_for_other_selections() {
+        let cache = cache(2, Duration::from_secs(60));
+        let url = Url::parse("https://example.com").unwrap();
+        insert(&cache, &url, &SelectionOptions::default());
+
+        let selection = SelectionOptions {
+            size_policy: crate::favicon_image::fetch::SizePolicy::Largest,
+            ..SelectionOptions::default()
+        };
+        assert!(cache.get(&url, &selection, 256).is_none());
+    }
+
+    #[test]
+    fn evicts_least_recently_used_and_keeps_expired() {
+        let selection = SelectionOptions::default();
+        let urls: Vec<Url> = ["https://a.com", "https://b.com", "https://c.com"]
+            .into_iter()
+            .map(|url| Url::parse(url).unwrap())
+            .collect();
+
+        let cache = cache(2, Duration::from_secs(60));
+        for url in &urls {
+            insert(&cache, url, &selection);
+        }
+        assert!(cache.get(&urls[0], &selection, 256).is_none());
+        assert!(cache.get(&urls[2], &selection, 256).is_some());
+
+        let cache = self::cache(2, Duration::ZERO);
+        insert(&cache, &urls[0], &selection);
+        std::thread::sleep(Duration::from_millis(1));
+        let expired = cache.get(&urls[0], &selection, 256).unwrap();
+        assert_eq!(expired.freshness, Freshness::Expired);
+        assert_eq!(expired.validators, validators(&urls[0]));
+    }
+
+    #[test]
+    fn evicts_to_fit_max_bytes() {
+        let selection = SelectionOptions::default();
+        let urls: Vec<Url> = ["https://a.com", "https://b.com", "https://c.com"]
+            .into_iter()
+            .map(|url| Url::parse(url).unwrap())
+            .collect();
+
+        // Each 4x4 rgba image uses 64 bytes
+        let cache = FaviconCache::new(
+            NonZeroUsize::new(10).unwrap(),
+            128,
+            Duration::from_secs(60),
+            Duration::ZERO,
+        );
+        for url in &urls {
+            insert(&cache, url, &selection);
+        }
+        assert!(cache.get(&urls[0], &selection, 256).is_none());
+        assert!(cache.get(&urls[1], &selection, 256).is_some());
+        assert!(cache.get(&urls[2], &selection, 256).is_some());
+        assert_eq!(cache.entries.lock().unwrap().bytes, 128);
+
+        // Replacing a favicon with one too large to cache removes it
+        let large = FaviconImage::from_data(DynamicImage::new_rgba8(8, 8));
+        cache.insert(&urls[1], &selection, 256, large, validators(&urls[1]));
+        assert!(cache.get(&urls[1], &selection, 256).is_none());
+        assert_eq!(cache.entries.lock().unwrap().bytes, 64);
+    }
+
+    #[test]
+    fn serves_stale_favicons_while_refreshing_once() {
+        let cache = FaviconCache::new(
+            NonZeroUsize::new(2).unwrap(),
+            usize::MAX,
+            Duration::ZERO,
+            Duration::from_secs(60),
+        );
+        let url = Url::parse("https://example.com").unwrap();
+        let selection = SelectionOptions::default();
+        insert(&cache, &url, &selection);
+        std::thread::sleep(Duration::from_millis(1));
+
+        let stale = cache.get(&url, &selection, 256).unwrap();
+        assert_eq!(stale.freshness, Freshness::Stale);
+        assert!(cache.begin_refresh(&url, &selection, 256));
+        assert!(!cache.begin_refresh(&url, &selection, 256));
+
+        // Failed and finished refreshes can be retried
+        cache.end_refresh(&url, &selection, 256);
+        assert!(cache.begin_refresh(&url, &selection, 256));
+        insert(&cache, &url, &selection);
+        assert!(cache.begin_refresh(&url, &selection, 256));
+    }
+}
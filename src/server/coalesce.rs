@@ -0,0 +1,124 @@
+//! Coalescing of concurrent fetches for the same favicon, so they share a single fetch
+
+use std::collections::HashMap;
+use std::future::Future;
+use std::sync::{Arc, Mutex};
+
+use tokio::sync::OnceCell;
+use url::Url;
+
+use crate::favicon_image::fetch::{FetchFaviconError, SelectionOptions};
+use crate::favicon_image::FaviconImage;
+
+use super::cache::CacheKey;
+
+type SharedResult = Result<FaviconImage, Arc<FetchFaviconError>>;
+type FetchCell = Arc<OnceCell<SharedResult>>;
+
+/// Fetches in progress, keyed by normalized target url, selection options and size.
+/// Size policies can choose different icons for different sizes, so only fetches for
+/// the same size are shared.
+#[derive(Debug, Default)]
+pub struct InFlightFetches {
+    fetches: Mutex<HashMap<CacheKey, FetchCell>>,
+}
+
+impl InFlightFetches {
+    /// Run a fetch, or wait for the result of the same fetch if one is already in progress.
+    /// If the request running a fetch is cancelled, a waiting request takes it over.
+    pub async fn run<F, Fut>(
+        &self,
+        url: &Url,
+        selection: &SelectionOptions,
+        size: u32,
+        fetch: F,
+    ) -> Result<FaviconImage, FetchFaviconError>
+    where
+        F: FnOnce() -> Fut,
+        Fut: Future<Output = Result<FaviconImage, FetchFaviconError>>,
+    {
+        let key = CacheKey::new(url, selection, size);
+        let fetch_cell = self
+            .fetches
+            .lock()
+            .unwrap()
+            .entry(key.clone())
+            .or_default()
+            .clone();
+
+        let result = fetch_cell
+            .get_or_init(|| async { fetch().await.map_err(Arc::new) })
+            .await
+            .clone();
+
+        // Later requests fetch again, unless a newer fetch has already replaced this one
+        let mut fetches = self.fetches.lock().unwrap();
+        if fetches
+            .get(&key)
+            .is_some_and(|current| Arc::ptr_eq(current, &fetch_cell))
+        {
+            fetches.remove(&key);
+        }
+
+        result.map_err(FetchFaviconError::Shared)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use image::DynamicImage;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::time::Duration;
+
+    async fn fetch(fetches: &AtomicUsize, size: u32) -> Result<FaviconImage, FetchFaviconError> {
+        fetches.fetch_add(1, Ordering::SeqCst);
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        Ok(FaviconImage::from_data(DynamicImage::new_rgba8(size, size)))
+    }
+
+    #[tokio::test]
+    async fn shares_concurrent_fetches() {
+        let in_flight = InFlightFetches::default();
+        let fetches = AtomicUsize::new(0);
+        let selection = SelectionOptions::default();
+        let url = Url::parse("https://example.com/#top").unwrap();
+        let normalized = Url::parse("https://EXAMPLE.com").unwrap();
+        let other = Url::parse("https://example.org").unwrap();
+
+        let (a, b, c) = tokio::join!(
+            in_flight.run(&url, &selection, 256, || fetch(&fetches, 256)),
+            in_flight.run(&normalized, &selection, 256, || fetch(&fetches, 256)),
+            in_flight.run(&other, &selection, 256, || fetch(&fetches, 256)),
+        );
+        assert!(a.is_ok() && b.is_ok() && c.is_ok());
+        assert_eq!(fetches.load(Ordering::SeqCst), 2);
+
+        // Finished fetches aren't reused
+        in_flight
+            .run(&url, &selection, 256, || fetch(&fetches, 256))
+            .await
+            .unwrap();
+        assert_eq!(fetches.load(Ordering::SeqCst), 3);
+        assert!(in_flight.fetches.lock().unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn only_shares_fetches_for_the_same_size() {
+        let in_flight = InFlightFetches::default();
+        let fetches = AtomicUsize::new(0);
+        let selection = SelectionOptions::default();
+        let url = Url::parse("https://example.com").unwrap();
+
+        let (large, small, same) = tokio::join!(
+            in_flight.run(&url, &selection, 256, || fetch(&fetches, 256)),
+            in_flight.run(&url, &selection, 16, || fetch(&fetches, 16)),
+            in_flight.run(&url, &selection, 16, || fetch(&fetches, 16)),
+        );
+        assert_eq!(large.unwrap().data.width(), 256);
+        assert_eq!(small.unwrap().data.width(), 16);
+        assert_eq!(same.unwrap().data.width(), 16);
+        assert_eq!(fetches.load(Ordering::SeqCst), 2);
+        assert!(in_flight.fetches.lock().unwrap().is_empty());
+    }
+}
@@ -13,5 +13,5 @@ pub fn generate_fallback(name: String, size: u32) -> FaviconImage {
         name.chars().next().unwrap_or('?').to_ascii_uppercase()
     );
 
-    FaviconImage::from_svg_str(fallback_svg, size)
+    FaviconImage::from_svg_str(fallback_svg, size).expect("Fallback svg should render")
 }
@@ -1,15 +1,20 @@
 use crate::favicon_image::fetch::FetchFaviconError;
-use crate::favicon_image::FaviconImage;
-use axum::http::{header, HeaderMap, HeaderName};
+use crate::favicon_image::{FaviconImage, ICO_MAX_SIZE};
+use axum::http::{header, HeaderMap, HeaderName, StatusCode};
 use axum::response::IntoResponse;
 use image::ImageFormat;
 
+use super::cache::CacheStatus;
 use super::fallback::generate_fallback;
 
+/// Longest error message sent in the `x-fallback-reason` header
+const MAX_FALLBACK_REASON_LENGTH: usize = 512;
+
 #[derive(Debug)]
 pub struct FaviconResponse {
     image: FaviconImage,
     headers: HeaderMap,
+    status: StatusCode,
 }
 
 impl FaviconResponse {
@@ -18,11 +23,22 @@ impl FaviconResponse {
         host: String,
         size: u32,
         format: ImageFormat,
+        ico_sizes: Vec<u32>,
     ) -> Self {
         // Construct response headers
         let mut headers = HeaderMap::new();
         headers.insert(header::CACHE_CONTROL, "max-age=604800".parse().unwrap());
 
+        // Ask browsers to send their preferred colour scheme
+        headers.insert(
+            HeaderName::from_static("accept-ch"),
+            "Sec-CH-Prefers-Color-Scheme".parse().unwrap(),
+        );
+        headers.insert(
+            header::VARY,
+            "Accept, Sec-CH-Prefers-Color-Scheme".parse().unwrap(),
+        );
+
         if let Err(error) = &res_value {
             headers.insert(
                 HeaderName::from_static("x-fallback"),
@@ -30,25 +46,90 @@ impl FaviconResponse {
             );
             headers.insert(
                 HeaderName::from_static("x-fallback-reason"),
-                error.to_string().parse().unwrap(),
+                // Svg and network errors can contain characters not allowed in headers
+                error
+                    .to_string()
+                    .replace(|c: char| !c.is_ascii() || c.is_ascii_control(), " ")
+                    .chars()
+                    .take(MAX_FALLBACK_REASON_LENGTH)
+                    .collect::<String>()
+                    .parse()
+                    .unwrap(),
             );
         }
 
-        // Get image or fallback w/ correct size
+        // Refused hosts are still sent a fallback, but with an error status
+        let status = match res_value.as_ref().map_err(FetchFaviconError::inner) {
+            Err(FetchFaviconError::HostNotAllowed(_)) => StatusCode::FORBIDDEN,
+            _ => StatusCode::OK,
+        };
+
+        // Get image or fallback w/ correct size. Each size of a multi-size ico is resized
+        // when it is written, so the largest is kept.
+        let writes_ico = format == ImageFormat::Ico && !ico_sizes.is_empty();
         let mut image = match res_value {
+            Ok(image) if writes_ico => image,
             Ok(image) => image.resize(size),
+            Err(_) if writes_ico => {
+                let largest = ico_sizes.iter().max().copied().unwrap_or(size);
+                generate_fallback(host, largest.min(ICO_MAX_SIZE))
+            }
             Err(_) => generate_fallback(host, size),
         };
 
         // Set desired format
-        image = image.reformat(format);
+        image = image.reformat(format).with_ico_sizes(ico_sizes);
 
-        Self { image, headers }
+        Self {
+            image,
+            headers,
+            status,
+        }
+    }
+
+    /// Report whether the favicon was served from the cache
+    pub fn with_cache_status(mut self, cache_status: Option<CacheStatus>) -> Self {
+        if let Some(cache_status) = cache_status {
+            self.headers.insert(
+                HeaderName::from_static("x-cache"),
+                cache_status.as_str().parse().unwrap(),
+            );
+        }
+        self
     }
 }
 
 impl IntoResponse for FaviconResponse {
     fn into_response(self) -> axum::response::Response {
-        (self.headers, self.image).into_response()
+        (self.status, self.headers, self.image).into_response()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::favicon_image::fetch::HostNotAllowed;
+    use std::sync::Arc;
+
+    fn status(error: FetchFaviconError) -> StatusCode {
+        FaviconResponse::from_fetch_result(
+            Err(error),
+            "example.com".to_owned(),
+            16,
+            ImageFormat::Png,
+            Vec::new(),
+        )
+        .status
+    }
+
+    #[test]
+    fn refuses_hosts_that_are_not_allowed() {
+        let not_allowed = || HostNotAllowed("example.com".to_owned()).into();
+        assert_eq!(status(not_allowed()), StatusCode::FORBIDDEN);
+        assert_eq!(
+            status(FetchFaviconError::Shared(Arc::new(not_allowed()))),
+            StatusCode::FORBIDDEN
+        );
+        assert_eq!(status(FetchFaviconError::CannotDecode), StatusCode::OK);
     }
 }
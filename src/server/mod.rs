@@ -1,18 +1,23 @@
 //! HTTP Server for fetching favicons by URL
 
+mod cache;
+mod coalesce;
 mod fallback;
 mod favicon_response;
 
 use std::collections::HashMap;
 use std::net::{AddrParseError, IpAddr, SocketAddr};
+use std::num::NonZeroUsize;
 use std::str::FromStr;
-use std::sync::OnceLock;
+use std::sync::{Arc, OnceLock};
+use std::time::Duration;
 
 use accept_header::Accept;
 use axum::extract::{Path, Query, State};
 use axum::http::{HeaderMap, Method};
 use axum::response::IntoResponse;
 use axum::{routing::get, Router};
+use clap::ValueEnum;
 use image::ImageFormat;
 use lazy_static::lazy_static;
 use mime::Mime;
@@ -28,25 +33,36 @@ use tracing_subscriber::util::SubscriberInitExt;
 use url::Url;
 
 use crate::cli_args::ServerOptions;
-use crate::favicon_image::fetch::FetchFaviconError;
-use crate::favicon_image::FaviconImage;
+use crate::favicon_image::fetch::{
+    AddressGuard, ColorScheme, FetchFaviconError, FetchOptions, FetchedFavicon, HostNotAllowed,
+    HostRules, IconRel, SelectionOptions, SizePolicy,
+};
+use crate::favicon_image::{FaviconImage, ICO_MAX_SIZE};
 use crate::DEFAULT_IMAGE_FORMAT;
 use crate::DEFAULT_IMAGE_SIZE;
 
+use self::cache::{CacheStatus, CachedFavicon, FaviconCache, Freshness};
+use self::coalesce::InFlightFetches;
 use self::favicon_response::FaviconResponse;
 
 lazy_static! {
     static ref SUPPORTED_OUTPUT_MIME_TYPES: Vec<Mime> = {
         use ImageFormat::*;
-        [
+        let formats = [
             Png, Jpeg, Gif, WebP, Pnm, Tiff, Tga, Dds, Bmp, Ico, Hdr, OpenExr, Farbfeld, Qoi,
-        ]
-        .into_iter()
-        .map(|format| Mime::from_str(format.to_mime_type()).unwrap())
-        .collect()
+        ];
+        #[cfg(feature = "avif")]
+        let formats = formats.into_iter().chain([Avif]);
+        formats
+            .into_iter()
+            .map(|format| Mime::from_str(format.to_mime_type()).unwrap())
+            .collect()
     };
 }
 
+/// Client hint header sent by browsers with the user's preferred colour scheme
+const SEC_CH_PREFERS_COLOR_SCHEME: &str = "sec-ch-prefers-color-scheme";
+
 enum CorsOrigin {
     Regex(Regex),
     String(String),
@@ -78,6 +94,10 @@ pub enum ServerError {
 #[derive(Debug, Clone)]
 struct ServerState {
     client: Client,
+    selection: SelectionOptions,
+    fetch_options: FetchOptions,
+    cache: Option<Arc<FaviconCache>>,
+    in_flight: Arc<InFlightFetches>,
 }
 
 pub async fn start_server(options: ServerOptions) -> Result<(), ServerError> {
@@ -127,8 +147,29 @@ pub async fn start_server(options: ServerOptions) -> Result<(), ServerError> {
     }
 
     // Create axum state
+    let fetch_options = FetchOptions {
+        address_guard: Some(AddressGuard {
+            allowed: options.allow_address,
+        }),
+        host_rules: HostRules {
+            allow: options.allow_host,
+            deny: options.deny_host,
+        },
+        ..options.fetch.into()
+    };
     let state = ServerState {
-        client: Client::new(),
+        client: fetch_options.build_client(),
+        selection: options.selection.into(),
+        fetch_options,
+        cache: NonZeroUsize::new(options.cache_capacity).map(|capacity| {
+            Arc::new(FaviconCache::new(
+                capacity,
+                options.cache_max_bytes,
+                Duration::from_secs(options.cache_ttl),
+                Duration::from_secs(options.cache_stale_window),
+            ))
+        }),
+        in_flight: Arc::default(),
     };
 
     // Define axum app
@@ -172,7 +213,55 @@ async fn get_favicon_handler(
     tracing::info!("Get favicon for {target_url_input:?}");
 
     // Determine requested size
-    let size: Option<u32> = params.get("size").and_then(|s| s.parse().ok());
+    let size: Option<u32> = params
+        .get("size")
+        .and_then(|s| s.parse().ok())
+        .filter(|s| *s > 0);
+
+    // Determine requested sizes of a multi-size ico
+    let ico_sizes: Vec<u32> = params
+        .get("ico_sizes")
+        .map(|sizes| {
+            sizes
+                .split(',')
+                .filter_map(|s| s.parse().ok())
+                .filter(|s| *s > 0)
+                .collect()
+        })
+        .unwrap_or_default();
+    // Determine how to choose between icon sizes
+    let selection = SelectionOptions {
+        size_policy: params
+            .get("size_policy")
+            .and_then(|policy| SizePolicy::from_str(policy, true).ok())
+            .unwrap_or(state.selection.size_policy),
+        rel_priority: params
+            .get("rel_priority")
+            .map(|rels| {
+                rels.split(',')
+                    .filter_map(|rel| IconRel::from_str(rel, true).ok())
+                    .collect()
+            })
+            .unwrap_or_else(|| state.selection.rel_priority.clone()),
+        color_scheme: params
+            .get("color_scheme")
+            .map(|scheme| scheme.as_str())
+            .or_else(|| {
+                headers
+                    .get(SEC_CH_PREFERS_COLOR_SCHEME)
+                    .and_then(|scheme| scheme.to_str().ok())
+                    .map(|scheme| scheme.trim_matches('"'))
+            })
+            .and_then(|scheme| ColorScheme::from_str(scheme, true).ok())
+            .unwrap_or(state.selection.color_scheme),
+    };
+
+    let fetch_size = ico_sizes
+        .iter()
+        .map(|&ico_size| ico_size.min(ICO_MAX_SIZE))
+        .chain(size)
+        .max()
+        .unwrap_or(DEFAULT_IMAGE_SIZE);
 
     // Determine requested format
     let format: Option<ImageFormat> = headers.get(axum::http::header::ACCEPT).and_then(|accept| {
@@ -190,17 +279,19 @@ async fn get_favicon_handler(
         .ok()
         .or_else(|| Url::parse(&format!("http://{}", target_url_input)).ok());
 
-    // Get the favicon
-    let favicon_res = match &target_url {
-        Some(target_url) => {
-            FaviconImage::fetch_for_url(
-                &state.client,
-                target_url,
-                size.unwrap_or(DEFAULT_IMAGE_SIZE),
-            )
-            .await
+    // Get the favicon, if the target host is allowed
+    let (favicon_res, cache_status) = match &target_url {
+        Some(target_url)
+            if !state
+                .fetch_options
+                .host_rules
+                .is_allowed(target_host(target_url)) =>
+        {
+            let not_allowed = HostNotAllowed(target_host(target_url).to_owned());
+            (Err(not_allowed.into()), None)
         }
-        None => Err(FetchFaviconError::InvalidUrl),
+        Some(target_url) => fetch_favicon(&state, target_url, fetch_size, &selection).await,
+        None => (Err(FetchFaviconError::InvalidUrl), None),
     };
 
     // Construct a response
@@ -211,5 +302,124 @@ async fn get_favicon_handler(
             .unwrap_or("?".to_owned()),
         size.unwrap_or(DEFAULT_IMAGE_SIZE),
         format.unwrap_or(DEFAULT_IMAGE_FORMAT),
+        ico_sizes,
     )
+    .with_cache_status(cache_status)
+}
+
+/// Fetch the favicon for a url, reusing a cached favicon if possible. Stale favicons are
+/// served immediately while they are revalidated in the background, and concurrent
+/// requests for the same favicon share a single fetch.
+async fn fetch_favicon(
+    state: &ServerState,
+    target_url: &Url,
+    fetch_size: u32,
+    selection: &SelectionOptions,
+) -> (Result<FaviconImage, FetchFaviconError>, Option<CacheStatus>) {
+    let Some(cache) = &state.cache else {
+        let fetch = || {
+            FaviconImage::fetch_for_url(
+                &state.client,
+                target_url,
+                fetch_size,
+                selection,
+                &state.fetch_options,
+            )
+        };
+        let favicon = state
+            .in_flight
+            .run(target_url, selection, fetch_size, fetch)
+            .await;
+        return (favicon, None);
+    };
+
+    match cache.get(target_url, selection, fetch_size) {
+        Some(cached) if cached.freshness == Freshness::Fresh => {
+            (Ok(cached.image), Some(CacheStatus::Hit))
+        }
+
+        Some(cached) if cached.freshness == Freshness::Stale => {
+            if cache.begin_refresh(target_url, selection, fetch_size) {
+                let (state, cache, stale) = (state.clone(), cache.clone(), cached.clone());
+                let (target_url, selection) = (target_url.clone(), selection.clone());
+                tokio::spawn(async move {
+                    let refreshed = fetch_and_cache(
+                        &state,
+                        &cache,
+                        &target_url,
+                        fetch_size,
+                        &selection,
+                        Some(stale),
+                    );
+                    if let Err(err) = refreshed.await {
+                        tracing::warn!("Failed to refresh favicon for {target_url}: {err}");
+                        cache.end_refresh(&target_url, &selection, fetch_size);
+                    }
+                });
+            }
+            (Ok(cached.image), Some(CacheStatus::Stale))
+        }
+
+        cached => {
+            let fetch = || fetch_and_cache(state, cache, target_url, fetch_size, selection, cached);
+            let favicon = state
+                .in_flight
+                .run(target_url, selection, fetch_size, fetch)
+                .await;
+            (favicon, Some(CacheStatus::Miss))
+        }
+    }
+}
+
+/// Fetch a favicon and store it in the cache. A previously cached favicon is revalidated,
+/// and kept for longer if it hasn't changed.
+async fn fetch_and_cache(
+    state: &ServerState,
+    cache: &FaviconCache,
+    target_url: &Url,
+    fetch_size: u32,
+    selection: &SelectionOptions,
+    cached: Option<CachedFavicon>,
+) -> Result<FaviconImage, FetchFaviconError> {
+    let (favicon, validators) = match cached {
+        Some(cached) => {
+            let revalidated = FaviconImage::revalidate(
+                &state.client,
+                target_url,
+                fetch_size,
+                selection,
+                &state.fetch_options,
+                &cached.validators,
+            )
+            .await?;
+            match revalidated {
+                FetchedFavicon::Modified(favicon, validators) => (favicon, validators),
+                FetchedFavicon::NotModified(validators) => (cached.image, validators),
+            }
+        }
+        None => {
+            FaviconImage::fetch_with_validators(
+                &state.client,
+                target_url,
+                fetch_size,
+                selection,
+                &state.fetch_options,
+            )
+            .await?
+        }
+    };
+    cache.insert(
+        target_url,
+        selection,
+        fetch_size,
+        favicon.clone(),
+        validators,
+    );
+
+    Ok(favicon)
+}
+
+/// Host of a target url, or an empty string for urls without a host
+fn target_host(url: &Url) -> &str {
+    url.host_str().unwrap_or_default()
 }